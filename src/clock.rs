@@ -0,0 +1,362 @@
+use std::path::{Path, PathBuf};
+
+use chrono::DateTime;
+
+use crate::discover::{self, NamePattern};
+use crate::index::Index;
+use crate::log::{self, Class};
+use crate::Record;
+
+/// One recorder session: a clock csv and the time span its wav files cover
+pub struct Session {
+    pub clock: PathBuf,
+    pub records: Vec<Record>,
+    /// Rates of the clean PPS intervals of `records`, see `interval_rates`
+    pub rates: Vec<(i64, f64)>,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Session {
+    pub fn name(&self) -> &str {
+        self.clock.file_stem().unwrap().to_str().unwrap()
+    }
+
+    /// Whether the session has audio between `start` and `end`
+    pub fn overlaps(&self, start: i64, end: i64) -> bool {
+        self.start <= end && start < self.end
+    }
+}
+
+/// Reads every clock csv in `clock_dir` as a session, ordered by start time. `rate` is the
+/// recorder's frame rate, used to extend each session to the edges of its first and last file,
+/// which are found among the recordings named by `patterns`.
+pub fn sessions<P: std::convert::AsRef<Path>>(
+    clock_dir: P,
+    input_dir: P,
+    patterns: &[NamePattern],
+    rate: f64,
+    index: &mut Index,
+) -> Vec<Session> {
+    let entries = match std::fs::read_dir(clock_dir.as_ref()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::error(
+                Class::Input,
+                format!("Reading clock dir {}: {e}", clock_dir.as_ref().display()),
+            );
+            return Vec::new();
+        }
+    };
+    let waves = discover::waves(input_dir.as_ref(), patterns);
+    let mut sessions = Vec::new();
+    for entry in entries.flatten() {
+        let clock = entry.path();
+        if clock.extension().is_none_or(|e| e != "csv") {
+            log::debug(format!("Skipping {}: not a clock csv", clock.display()));
+            continue;
+        }
+        let records = read_records(&clock);
+        let (Some(first), Some(last)) = (records.first(), records.last()) else {
+            log::error(
+                Class::Input,
+                format!("Failed to read clock csv {}", clock.display()),
+            );
+            continue;
+        };
+        let start = first.time - (first.file_sample as f64 / rate * 1e9).round() as i64;
+        let tail = index
+            .wav_info(
+                discover::find(&waves, &last.file).unwrap_or(&input_dir.as_ref().join(&last.file)),
+            )
+            .map_or(0, |(duration, _)| duration.saturating_sub(last.file_sample));
+        let end = last.time + (tail as f64 / rate * 1e9).round() as i64;
+        sessions.push(Session {
+            clock,
+            rates: interval_rates(&records),
+            records,
+            start,
+            end,
+        });
+    }
+    sessions.sort_by_key(|s| s.start);
+    sessions
+}
+
+pub fn read_records<P: std::convert::AsRef<Path>>(clock: P) -> Vec<Record> {
+    match csv::Reader::from_path(clock) {
+        Ok(mut reader) => reader.deserialize().flatten().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Fractional frequency spread assumed when there are too few PPS intervals to measure it
+const DEFAULT_STABILITY: f64 = 50e-6;
+/// PPS intervals around a point used to estimate the local frame rate
+const RATE_INTERVALS: usize = 10;
+
+/// Where the recording was at some instant according to the clock model
+pub struct Fix {
+    /// Recording-wide frame position
+    pub sample: f64,
+    /// Index of the last record at or before the instant (or the first record)
+    pub record: usize,
+    /// Estimated 1-sigma timing error in nanos
+    pub uncertainty: f64,
+}
+
+/// Frame rates measured over clean one second PPS intervals, with their mid times. Computed
+/// once per clock and passed to `locate` and the functions built on it.
+pub fn interval_rates(records: &[Record]) -> Vec<(i64, f64)> {
+    records
+        .windows(2)
+        .filter_map(|w| {
+            let dt = w[1].time - w[0].time;
+            ((dt - 1_000_000_000).abs() < 10_000_000).then(|| {
+                let frames = w[1].sample as f64 - w[0].sample as f64;
+                (w[0].time + dt / 2, frames / dt as f64 * 1e9)
+            })
+        })
+        .collect()
+}
+
+/// Mean rate and fractional spread of the time-ordered `rates` nearest `nanos`, falling
+/// back to the nominal `rate`
+fn local_rate(rates: &[(i64, f64)], nanos: i64, rate: f64) -> (f64, f64) {
+    // Widen a window around `nanos` towards the nearer neighbour, the earlier on a tie
+    let mut lo = rates.partition_point(|(t, _)| *t < nanos);
+    let mut hi = lo;
+    while hi - lo < RATE_INTERVALS.min(rates.len()) {
+        if lo > 0 && (hi == rates.len() || nanos - rates[lo - 1].0 <= rates[hi].0 - nanos) {
+            lo -= 1;
+        } else {
+            hi += 1;
+        }
+    }
+    let rates = &rates[lo..hi];
+    if rates.len() < 2 {
+        return (rates.first().map_or(rate, |r| r.1), DEFAULT_STABILITY);
+    }
+    let n = rates.len() as f64;
+    let mean = rates.iter().map(|r| r.1).sum::<f64>() / n;
+    let var = rates.iter().map(|r| (r.1 - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    (mean, var.sqrt() / mean)
+}
+
+/// Locates `nanos` in the recording: between records by interpolating the frame counter
+/// (which keeps running through PPS dropouts), past either end by holding over at the rate
+/// measured on the nearest clean intervals. The uncertainty grows with the distance to the
+/// nearest record at the measured frequency spread. `rates` are the `interval_rates` of
+/// `records`.
+pub fn locate(records: &[Record], rates: &[(i64, f64)], nanos: i64, rate: f64) -> Option<Fix> {
+    let first = records.first()?;
+    let i = records.partition_point(|r| r.time <= nanos);
+    let (mean, spread) = local_rate(rates, nanos, rate);
+    let (record, sample, distance) = if i == 0 {
+        let sample = first.sample as f64 - (first.time - nanos) as f64 * mean / 1e9;
+        (0, sample, first.time - nanos)
+    } else if i == records.len() {
+        let last = &records[i - 1];
+        let sample = last.sample as f64 + (nanos - last.time) as f64 * mean / 1e9;
+        (i - 1, sample, nanos - last.time)
+    } else {
+        let (a, b) = (&records[i - 1], &records[i]);
+        let t = (nanos - a.time) as f64 / (b.time - a.time) as f64;
+        let sample = a.sample as f64 + t * (b.sample as f64 - a.sample as f64);
+        (i - 1, sample, (nanos - a.time).min(b.time - nanos))
+    };
+    Some(Fix {
+        sample,
+        record,
+        uncertainty: distance as f64 * spread,
+    })
+}
+
+/// Fractional recording-wide sample position at `nanos`
+pub fn sample_at(records: &[Record], rates: &[(i64, f64)], nanos: i64, rate: f64) -> Option<f64> {
+    locate(records, rates, nanos, rate).map(|f| f.sample)
+}
+
+/// Worst timing uncertainty between `start` and `end`, found at the ends or in the middle
+/// of a PPS gap
+pub fn uncertainty(
+    records: &[Record],
+    rates: &[(i64, f64)],
+    start: i64,
+    end: i64,
+    rate: f64,
+) -> f64 {
+    let gaps = records
+        .windows(2)
+        .filter(|w| w[1].time - w[0].time > 1_500_000_000)
+        .map(|w| w[0].time + (w[1].time - w[0].time) / 2)
+        .filter(|mid| (start..end).contains(mid));
+    [start, end]
+        .into_iter()
+        .chain(gaps)
+        .filter_map(|t| locate(records, rates, t, rate))
+        .map(|f| f.uncertainty)
+        .fold(0.0, f64::max)
+}
+
+/// Number of recorded frames between `start` and `end` according to the clock
+pub fn frames_between(
+    records: &[Record],
+    rates: &[(i64, f64)],
+    start: i64,
+    end: i64,
+    rate: f64,
+) -> Option<f64> {
+    Some(sample_at(records, rates, end, rate)? - sample_at(records, rates, start, rate)?)
+}
+
+/// Parses an RFC 3339 timestamp or a plain number of nanoseconds since the epoch.
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    if let Ok(nanos) = s.parse::<i64>() {
+        return Ok(nanos);
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map_err(|e| format!("expected RFC 3339 time or nanoseconds: {e}"))?
+        .timestamp_nanos_opt()
+        .ok_or_else(|| "time out of range".to_owned())
+}
+
+/// Parses a duration such as `90s`, `1.5m`, `2h` or `250ms` into nanoseconds;
+/// a bare number is taken as seconds.
+pub fn parse_duration(s: &str) -> Result<i64, String> {
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| format!("invalid duration '{s}'"))?;
+    let scale = match unit.trim() {
+        "ns" => 1.0,
+        "us" => 1e3,
+        "ms" => 1e6,
+        "" | "s" => 1e9,
+        "m" | "min" => 60e9,
+        "h" => 3600e9,
+        unit => return Err(format!("unknown duration unit '{unit}'")),
+    };
+    Ok((value * scale).round() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s"), Ok(90_000_000_000));
+        assert_eq!(parse_duration("1.5m"), Ok(90_000_000_000));
+        assert_eq!(parse_duration("250ms"), Ok(250_000_000));
+        assert_eq!(parse_duration("50us"), Ok(50_000));
+        assert_eq!(parse_duration("2h"), Ok(7_200_000_000_000));
+        assert_eq!(parse_duration("3"), Ok(3_000_000_000));
+        assert!(parse_duration("3 days").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(
+            parse_time("2024-06-12T10:03:00Z"),
+            Ok(1_718_186_580_000_000_000)
+        );
+        assert_eq!(
+            parse_time("2024-06-12T12:03:00.5+02:00"),
+            Ok(1_718_186_580_500_000_000)
+        );
+        assert_eq!(
+            parse_time("1718186580000000000"),
+            Ok(1_718_186_580_000_000_000)
+        );
+        assert!(parse_time("2024-06-12 10:03").is_err());
+    }
+
+    const T0: i64 = 1_718_186_580_000_000_000;
+
+    /// Records one second apart at `rate` frames per second, leaving out `missing` seconds
+    fn records(seconds: i64, rate: f64, missing: &[i64]) -> Vec<Record> {
+        (0..seconds)
+            .filter(|s| !missing.contains(s))
+            .map(|s| Record {
+                time: T0 + s * 1_000_000_000,
+                sample: (s as f64 * rate).round() as u64,
+                file_sample: (s as f64 * rate).round() as u32,
+                file: "1718186579700000000.wav".to_owned(),
+            })
+            .collect()
+    }
+
+    fn locate_in(records: &[Record], nanos: i64) -> Fix {
+        locate(records, &interval_rates(records), nanos, 48000.0).unwrap()
+    }
+
+    #[test]
+    fn locates_between_records() {
+        let records = records(10, 48001.0, &[]);
+        let fix = locate_in(&records, T0 + 2_500_000_000);
+        assert_eq!(fix.record, 2);
+        assert!((fix.sample - 2.5 * 48001.0).abs() < 1e-6);
+        // Every interval ran at the same rate, so there is no spread to grow with
+        assert!(fix.uncertainty.abs() < 1e-6);
+    }
+
+    #[test]
+    fn holds_over_at_the_measured_rate() {
+        let records = records(10, 48001.0, &[]);
+        let after = locate_in(&records, T0 + 11_000_000_000);
+        assert_eq!(after.record, 9);
+        assert!((after.sample - 11.0 * 48001.0).abs() < 1e-3);
+        let before = locate_in(&records, T0 - 1_000_000_000);
+        assert_eq!(before.record, 0);
+        assert!((before.sample + 48001.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn uncertainty_grows_into_a_pps_gap() {
+        let mut records = records(20, 48000.0, &[8, 9, 10, 11]);
+        // Jitter the rate so the spread is measurable
+        for (i, r) in records.iter_mut().enumerate() {
+            r.sample += (i % 2) as u64;
+        }
+        let rates = interval_rates(&records);
+        let near = locate(&records, &rates, T0 + 7_100_000_000, 48000.0).unwrap();
+        let middle = locate(&records, &rates, T0 + 9_500_000_000, 48000.0).unwrap();
+        assert!(middle.uncertainty > near.uncertainty);
+        assert!(
+            uncertainty(&records, &rates, T0, T0 + 19_000_000_000, 48000.0) >= middle.uncertainty
+        );
+        // The frame counter keeps running through the gap
+        assert!((middle.sample - 9.5 * 48000.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn falls_back_to_the_nominal_rate() {
+        assert!(locate(&[], &[], T0, 48000.0).is_none());
+        let records = records(1, 48000.0, &[]);
+        let fix = locate_in(&records, T0 + 1_000_000_000);
+        assert!((fix.sample - 48000.0).abs() < 1e-6);
+        assert!((fix.uncertainty - 1e9 * DEFAULT_STABILITY).abs() < 1e-3);
+    }
+
+    #[test]
+    fn averages_the_nearest_intervals() {
+        // One interval a second, measuring its own index as the rate
+        let rates = (0..30)
+            .map(|i| (T0 + i * 1_000_000_000, i as f64))
+            .collect::<Vec<_>>();
+        let mean = |nanos| local_rate(&rates, nanos, 48000.0).0;
+        // Intervals 1 to 10
+        assert_eq!(mean(T0 + 5_500_000_000), 5.5);
+        // Intervals 0 to 9, the earlier of the two at the same distance
+        assert_eq!(mean(T0 + 5_000_000_000), 4.5);
+        // The first or last ten past either end
+        assert_eq!(mean(T0 - 1), 4.5);
+        assert_eq!(mean(T0 + 100_000_000_000), 24.5);
+        assert_eq!(local_rate(&rates[..1], T0, 48000.0).0, 0.0);
+    }
+}
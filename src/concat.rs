@@ -1,14 +1,120 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use crate::discover::{self, NamePattern};
+use crate::index::Index;
+use crate::log::{self, Class};
+use crate::manifest::Provenance;
+use crate::name::{self, Fields};
+use crate::output::{Options, Writer};
+use crate::plan::Plan;
+use crate::reader::{Event, TimedSampleReader};
+use crate::timing::{PpsTracker, Timing};
 use crate::Record;
 
-pub fn concat<P: std::convert::AsRef<Path>>(input_dir: P, output: P, clock: P, step: usize) {
-    let mut waves = std::fs::read_dir(input_dir.as_ref())
-        .unwrap()
-        .flat_map(|f| f.map(|e| e.path()))
+/// Inputs and output of a concat
+struct Resolved {
+    /// Files from the one the clock starts in
+    waves: Vec<PathBuf>,
+    records: Vec<Record>,
+    /// File of the last clock record
+    end_file: Option<PathBuf>,
+    start_nanos: i64,
+    output: String,
+}
+
+fn resolve(
+    input_dir: &Path,
+    patterns: &[NamePattern],
+    output: &Path,
+    clock: &Path,
+    options: &Options,
+) -> Option<Resolved> {
+    let mut recordings = discover::recordings(input_dir, patterns);
+    let clock_start = clock.file_stem().unwrap();
+    let first = recordings
+        .iter()
+        .position(|r| r.path.file_stem() == Some(clock_start))?;
+    let mut start_nanos = recordings[first].nanos;
+    let waves = recordings
+        .drain(first..)
+        .map(|r| r.path)
         .collect::<Vec<_>>();
-    waves.sort_unstable();
 
+    let mut end_file = None;
+    let mut reader = csv::Reader::from_path(clock).unwrap();
+    let records = reader.deserialize().flatten().collect::<Vec<Record>>();
+    if let Some(r) = records.first() {
+        start_nanos = r.time - (r.sample as f64 / 48000.0 * 1e9).round() as i64;
+    }
+    if let Some(Record { file, .. }) = records.last() {
+        end_file = discover::find(&waves, file).cloned();
+    }
+
+    let output_path = output.parent().unwrap().to_str().unwrap();
+    let output_stem = output.file_stem().unwrap().to_str().unwrap();
+    let output_ext = options.format.extension();
+    let fields = Fields {
+        name: Some(output_stem.to_owned()),
+        start: Some(start_nanos),
+        ..options.fields.clone()
+    };
+    let output_name = name::file_name(options.name_template.as_ref(), "{name}_{start}", &fields);
+    Some(Resolved {
+        waves,
+        records,
+        end_file,
+        start_nanos,
+        output: format!("{output_path}/{output_name}.{output_ext}"),
+    })
+}
+
+/// What `concat` would read and write
+pub fn plan<P: std::convert::AsRef<Path>>(
+    input_dir: P,
+    patterns: &[NamePattern],
+    output: P,
+    clock: P,
+    step: usize,
+    options: &Options,
+    index: &mut Index,
+) -> Plan {
+    let Some(resolved) = resolve(
+        input_dir.as_ref(),
+        patterns,
+        output.as_ref(),
+        clock.as_ref(),
+        options,
+    ) else {
+        let mut plan = Plan::new(&output.as_ref().display().to_string());
+        plan.error("clock start not found");
+        return plan;
+    };
+    let mut plan = Plan::new(&resolved.output);
+    for wav in &resolved.waves {
+        let name = wav.file_name().unwrap().to_string_lossy().into_owned();
+        // Concat skips unreadable files like the reader does
+        if let Some((duration, spec)) = index.wav_info(wav) {
+            plan.start.get_or_insert((name.clone(), 0));
+            plan.samples += (duration as u64 * spec.channels as u64).div_ceil(step as u64);
+            plan.sources.push(name);
+        } else {
+            plan.warn(format!("skipping unreadable {name}"));
+        }
+        if resolved.end_file.as_ref() == Some(wav) {
+            break;
+        }
+    }
+    plan
+}
+
+pub fn concat<P: std::convert::AsRef<Path>>(
+    input_dir: P,
+    patterns: &[NamePattern],
+    output: P,
+    clock: P,
+    step: usize,
+    options: &Options,
+) {
     let spec = hound::WavSpec {
         channels: 1,
         sample_rate: 48000,
@@ -16,58 +122,74 @@ pub fn concat<P: std::convert::AsRef<Path>>(input_dir: P, output: P, clock: P, s
         sample_format: hound::SampleFormat::Int,
     };
 
-    let clock_start_nanos_str = clock.as_ref().file_stem().unwrap().to_str().unwrap();
-
-    let mut wav_iter = waves.iter().peekable();
-    while let Some(wav) = wav_iter.peek() {
-        if wav.file_stem().unwrap().to_str().unwrap() == clock_start_nanos_str {
-            break;
-        }
-        wav_iter.next();
-    }
-
-    if wav_iter.peek().is_none() {
-        eprintln!("Clock start not found");
+    let Some(Resolved {
+        waves,
+        records,
+        end_file,
+        start_nanos,
+        output,
+    }) = resolve(
+        input_dir.as_ref(),
+        patterns,
+        output.as_ref(),
+        clock.as_ref(),
+        options,
+    )
+    else {
+        log::error(Class::Input, "Clock start not found");
         return;
-    }
-
-    let mut start_nanos = clock_start_nanos_str.parse::<i64>().unwrap();
-    let mut end_file = "".to_owned();
-    let mut reader = csv::Reader::from_path(clock).unwrap();
-    if let Some(record) = reader.deserialize().next() {
-        let r: Record = record.unwrap();
-        start_nanos = r.time - (r.sample as f64 / 48000.0 * 1e9).round() as i64;
-    }
-    if let Some(Ok(Record { file, .. })) = reader.deserialize().last() {
-        end_file = file;
-    }
-
+    };
     let start = chrono::DateTime::from_timestamp_nanos(start_nanos);
 
-    let output_path = output.as_ref().parent().unwrap().to_str().unwrap();
-    let output_stem = output.as_ref().file_stem().unwrap().to_str().unwrap();
-    let output_ext = output.as_ref().extension().unwrap().to_str().unwrap();
-
-    let mut writer = hound::WavWriter::create(
-        format!("{output_path}/{output_stem}_{}.{output_ext}", start.to_rfc3339()),
-        spec,
-    )
-    .unwrap();
+    let mut options = options.clone();
+    options
+        .comments
+        .push(("start_time".to_owned(), start.to_rfc3339()));
+    let Ok(mut writer) = Writer::create(output, spec, &options) else {
+        return;
+    };
 
-    for wav in wav_iter {
-        let mut reader = match hound::WavReader::open(wav) {
-            Ok(r) => r,
-            Err(_e) => {
-                continue;
+    let mut provenance = Provenance {
+        clocks: vec![clock.as_ref().display().to_string()],
+        achieved_start: Some(start_nanos),
+        ..Default::default()
+    };
+    let mut reader =
+        TimedSampleReader::new(input_dir.as_ref(), patterns, spec.sample_rate as f64, step);
+    reader.skip_unreadable();
+    reader.seek(&waves[0], 0, end_file.as_deref(), &records);
+    let mut tracker = None;
+    let mut written = 0u64;
+    while let Some(event) = reader.next_event() {
+        match event {
+            Event::Sample(sample) => {
+                if writer.write_sample(sample).is_err() {
+                    return;
+                }
+                written += 1;
             }
-        };
-
-        reader.samples::<i32>().step_by(step).for_each(|s| {
-            writer.write_sample(s.unwrap()).unwrap();
-        });
-
-        if wav.to_str().unwrap() == end_file {
-            break;
+            Event::File {
+                file,
+                seek,
+                channels,
+            } => {
+                let tracker = tracker.get_or_insert_with(|| {
+                    PpsTracker::new(&records, channels as f64 / step as f64)
+                });
+                tracker.file(
+                    file.file_name().unwrap().to_str().unwrap(),
+                    seek,
+                    written as i64,
+                );
+            }
+            Event::Session { .. } | Event::Gap { .. } => {}
         }
     }
+    provenance.sources = reader.finish().0.sources;
+    let timing = Timing {
+        start_nanos: Some(start_nanos),
+        pps: tracker.map(|t| t.finish(written)).unwrap_or_default(),
+        uncertainty: None,
+    };
+    let _ = writer.finalize(&timing, &provenance);
 }
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::discover::NamePattern;
+use crate::name::Template;
+use crate::output::Format;
+use crate::profile::{DeviceProfile, Profiles};
+use crate::track::ModulePosition;
+use crate::ClockSource;
+
+/// A `wave.toml` describing a measurement campaign. Relative paths are resolved against
+/// `root`, which itself is relative to the directory of the file.
+///
+/// ```toml
+/// root = "/data/campaign"
+/// output_dir = "cuts"
+/// cuts = "cuts.csv"
+/// mode = "umc"
+/// format = "flac"
+/// name_template = "{flight}_{range}_D{module}_{start}"
+///
+/// [flights.3]
+/// track = "tracks/flight3.gpx"
+///
+/// [profiles.umc96]
+/// sample_rate = 96000
+/// step = 2
+/// pps = { marker = 0xeeeeeeee }
+///
+/// [[modules]]
+/// module = 1
+/// input_dir = "m1/wav"
+/// clock_dir = "m1/clock"
+/// lat = 48.0
+/// lon = 11.0
+/// alt = 500.0
+/// heading = 90.0
+/// ```
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub root: PathBuf,
+    pub output_dir: Option<PathBuf>,
+    pub cuts: Option<PathBuf>,
+    pub mode: Option<String>,
+    pub format: Option<Format>,
+    #[serde(default, deserialize_with = "template")]
+    pub name_template: Option<Template>,
+    pub clock_source: Option<ClockSource>,
+    /// How recording file names give their start time, tried in order
+    pub name_patterns: Option<Vec<NamePattern>>,
+    #[serde(default)]
+    pub timestamps: bool,
+    #[serde(default)]
+    pub resample: bool,
+    /// Track used for flights without their own
+    pub track: Option<PathBuf>,
+    #[serde(default)]
+    pub flights: BTreeMap<String, Flight>,
+    /// Recorders beyond the built-in modes
+    #[serde(default)]
+    pub profiles: BTreeMap<String, DeviceProfile>,
+    pub modules: Vec<Module>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Flight {
+    pub track: Option<PathBuf>,
+}
+
+/// One recorder of the array, with where its data lives and where it stood
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Module {
+    pub module: u8,
+    pub input_dir: PathBuf,
+    pub clock_dir: Option<PathBuf>,
+    /// Overrides the campaign mode
+    pub mode: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub alt: Option<f64>,
+    #[serde(default)]
+    pub heading: f64,
+}
+
+fn template<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Template>, D::Error> {
+    let s: String = serde::Deserialize::deserialize(d)?;
+    Template::parse(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl Module {
+    pub fn position(&self) -> Option<ModulePosition> {
+        Some(ModulePosition {
+            module: self.module,
+            lat: self.lat?,
+            lon: self.lon?,
+            alt: self.alt.unwrap_or(0.0),
+            heading: self.heading,
+        })
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut config: Config = toml::from_str(&text).map_err(|e| e.to_string())?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        let root = base.join(&config.root);
+        let resolve = |p: &mut PathBuf| *p = root.join(&*p);
+        let flights = config.flights.values_mut().map(|f| &mut f.track);
+        for p in [&mut config.output_dir, &mut config.cuts, &mut config.track]
+            .into_iter()
+            .chain(flights)
+            .flatten()
+        {
+            resolve(p);
+        }
+        for m in &mut config.modules {
+            resolve(&mut m.input_dir);
+            if let Some(p) = &mut m.clock_dir {
+                resolve(p);
+            }
+        }
+        config.root = root;
+
+        for (name, profile) in &config.profiles {
+            profile
+                .check()
+                .map_err(|e| format!("profile {name}: {e}"))?;
+        }
+        let profiles = Profiles::with(&config.profiles);
+        let mut seen = Vec::new();
+        for m in &config.modules {
+            if seen.contains(&m.module) {
+                return Err(format!("module {} is configured twice", m.module));
+            }
+            seen.push(m.module);
+            if let Some(mode) = m.mode.as_ref().or(config.mode.as_ref()) {
+                profiles
+                    .get(mode)
+                    .map_err(|e| format!("module {}: {e}", m.module))?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Track of `flight`, falling back to the campaign track
+    pub fn track(&self, flight: Option<&str>) -> Option<&Path> {
+        flight
+            .and_then(|f| self.flights.get(f))
+            .and_then(|f| f.track.as_deref())
+            .or(self.track.as_deref())
+    }
+
+    /// Positions of the modules which have one
+    pub fn positions(&self) -> Vec<ModulePosition> {
+        self.modules.iter().filter_map(Module::position).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const EXAMPLE: &str = r#"
+        root = "campaign"
+        output_dir = "cuts"
+        mode = "umc96"
+        format = "flac"
+        name_template = "{flight}_D{module}"
+        track = "tracks/all.gpx"
+
+        [flights.3]
+        track = "tracks/flight3.gpx"
+
+        [profiles.umc96]
+        sample_rate = 96000
+        step = 2
+
+        [[modules]]
+        module = 1
+        input_dir = "m1/wav"
+        clock_dir = "/data/m1/clock"
+        lat = 48.0
+        lon = 11.0
+
+        [[modules]]
+        module = 2
+        input_dir = "m2/wav"
+        mode = "i2s"
+    "#;
+
+    #[test]
+    fn loads_campaigns_relative_to_their_root() {
+        let dir = testing::dir();
+        let path = dir.path().join("wave.toml");
+        std::fs::write(&path, EXAMPLE).unwrap();
+        let config = Config::load(&path).unwrap();
+
+        let root = dir.path().join("campaign");
+        assert_eq!(config.root, root);
+        assert_eq!(config.output_dir, Some(root.join("cuts")));
+        assert_eq!(config.modules[0].input_dir, root.join("m1/wav"));
+        assert_eq!(
+            config.modules[0].clock_dir.as_deref(),
+            Some(Path::new("/data/m1/clock"))
+        );
+        assert_eq!(config.format, Some(Format::Flac));
+        assert!(config
+            .name_template
+            .as_ref()
+            .is_some_and(|t| t.uses("flight")));
+        assert_eq!(config.profiles["umc96"].sample_rate, 96000);
+
+        let flight3 = root.join("tracks/flight3.gpx");
+        assert_eq!(config.track(Some("3")), Some(flight3.as_path()));
+        let all = root.join("tracks/all.gpx");
+        assert_eq!(config.track(Some("4")), Some(all.as_path()));
+        assert_eq!(config.track(None), Some(all.as_path()));
+
+        let positions = config.positions();
+        assert_eq!(positions.len(), 1);
+        assert_eq!((positions[0].module, positions[0].alt), (1, 0.0));
+    }
+
+    #[test]
+    fn rejects_inconsistent_campaigns() {
+        let dir = testing::dir();
+        let path = dir.path().join("wave.toml");
+        let load = |text: &str| {
+            std::fs::write(&path, text).unwrap();
+            Config::load(&path)
+        };
+        let twice = EXAMPLE.replace("module = 2", "module = 1");
+        assert_eq!(load(&twice).unwrap_err(), "module 1 is configured twice");
+        let unknown = EXAMPLE.replace(r#"mode = "i2s""#, r#"mode = "umc9""#);
+        assert!(load(&unknown).unwrap_err().starts_with("module 2: "));
+        let typo = EXAMPLE.replace("output_dir", "outptu_dir");
+        assert!(load(&typo).unwrap_err().contains("outptu_dir"));
+        let template = EXAMPLE.replace("{flight}", "{fligth}");
+        assert!(load(&template).is_err());
+    }
+}
@@ -1,23 +1,153 @@
 use std::path::Path;
 
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressStyle;
 
-pub fn make_wav<P: std::convert::AsRef<Path>>(output: P, input: P, start: u32, samples: u64) {
-    let spec = hound::WavSpec {
-        channels: 1,
-        sample_rate: 48000,
-        bits_per_sample: 32,
-        sample_format: hound::SampleFormat::Int,
+use crate::log::{self, Class};
+use crate::manifest::Provenance;
+use crate::name::Fields;
+use crate::output::{Options, Writer};
+use crate::pps::{get_pps, Pps, PpsLayout};
+use crate::repair;
+use crate::timing::{PpsMark, Timing};
+
+/// Where the cut starts in the input file
+pub enum Position {
+    /// Frame index
+    Sample(u32),
+    /// UTC nanos, located with the file's PPS markers
+    Time(i64),
+}
+
+/// How much of the input file to write
+pub enum Length {
+    /// Number of samples (all channels)
+    Samples(u64),
+    /// Nanoseconds
+    Duration(i64),
+    /// UTC nanos, located with the file's PPS markers
+    Until(i64),
+}
+
+/// Fractional frame index at `nanos`, interpolated between the surrounding PPS markers
+/// and extrapolated at the nominal `rate` past either end.
+fn frame_at(pps: &[(f64, i64)], nanos: i64, rate: f64) -> f64 {
+    let i = pps
+        .partition_point(|(_, n)| *n <= nanos)
+        .clamp(1, pps.len() - 1);
+    let ((fa, na), (fb, nb)) = (pps[i - 1], pps[i]);
+    let rate = if nb > na {
+        (fb - fa) / (nb - na) as f64 * 1e9
+    } else {
+        rate
     };
+    fa + (nanos - na) as f64 * rate / 1e9
+}
 
-    let mut samples = samples;
+pub fn make_wav<P: std::convert::AsRef<Path>>(
+    output: P,
+    input: P,
+    start: Position,
+    length: Length,
+    layout: &PpsLayout,
+    options: &Options,
+) {
+    let mut reader = match repair::open(input.as_ref()) {
+        Ok(reader) => reader,
+        Err(e) => {
+            log::error(
+                Class::Io,
+                format!("Error reading file {}: {e}", input.as_ref().display()),
+            );
+            return;
+        }
+    };
+    let spec = reader.spec();
+    let channels = spec.channels as u64;
+    let rate = spec.sample_rate as f64;
+
+    // Timestamps need the markers even when the cut itself is placed by sample
+    let needs_pps = matches!(start, Position::Time(_))
+        || matches!(length, Length::Until(_))
+        || options.timestamps;
+    let pps = if needs_pps {
+        get_pps(input.as_ref(), layout)
+            .iter()
+            .map(|Pps { nanos, sample, .. }| ((*sample as u64 / channels) as f64, *nanos))
+            .collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+    if needs_pps && pps.is_empty() {
+        log::error(Class::Input, "No PPS markers found in input");
+        return;
+    }
+    // A single marker still anchors the file, at the nominal rate
+    let anchors = if pps.len() == 1 {
+        vec![pps[0], (pps[0].0 + rate, pps[0].1 + 1_000_000_000)]
+    } else {
+        pps.clone()
+    };
+
+    let start_frame = match start {
+        Position::Sample(sample) => sample as f64,
+        Position::Time(nanos) => frame_at(&anchors, nanos, rate),
+    };
+    if start_frame < 0.0 || start_frame > reader.duration() as f64 {
+        log::error(Class::OutOfRange, "Requested start not in file");
+        return;
+    }
+    let start_frame = start_frame.round() as u32;
+    let mut samples = match length {
+        Length::Samples(samples) => samples,
+        Length::Duration(nanos) => (nanos as f64 / 1e9 * rate).round() as u64 * channels,
+        Length::Until(nanos) => {
+            let end_frame = frame_at(&anchors, nanos, rate).round().max(0.0) as u64;
+            end_frame.saturating_sub(start_frame as u64) * channels
+        }
+    };
+
+    if let Err(e) = reader.seek(start_frame) {
+        log::error(
+            Class::Io,
+            format!("Error reading file {}: {e}", input.as_ref().display()),
+        );
+        return;
+    }
 
-    let mut reader = hound::WavReader::open(input).unwrap();
-    let mut writer = hound::WavWriter::create(output, spec).unwrap();
+    let mut options = options.clone();
+    options.comments.extend([
+        (
+            "source".to_owned(),
+            input.as_ref().to_string_lossy().into_owned(),
+        ),
+        ("start_sample".to_owned(), start_frame.to_string()),
+    ]);
 
-    reader.seek(start).unwrap();
+    let ext = options.format.extension();
+    let output = match &options.name_template {
+        Some(template) => {
+            let fields = Fields {
+                name: output
+                    .as_ref()
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned()),
+                start: match start {
+                    Position::Time(nanos) => Some(nanos),
+                    Position::Sample(_) => None,
+                },
+                ..options.fields.clone()
+            };
+            let name = template.render(&fields);
+            // Times in names may contain dots, so the extension is appended rather than set
+            output.as_ref().with_file_name(format!("{name}.{ext}"))
+        }
+        None => output.as_ref().with_extension(ext),
+    };
+    let Ok(mut writer) = Writer::create(output, spec, &options) else {
+        return;
+    };
 
-    let pb = ProgressBar::new(samples);
+    let pb = log::progress_bar(samples);
     let t = (samples as f64).log10().ceil() as u64;
     pb.set_style(
         ProgressStyle::with_template(&format!(
@@ -28,15 +158,138 @@ pub fn make_wav<P: std::convert::AsRef<Path>>(output: P, input: P, start: u32, s
     );
 
     for s in reader.samples::<i32>() {
-        if samples != 0 {
-            writer.write_sample(s.unwrap()).unwrap();
-            samples -= 1;
-            pb.inc(1);
-        } else {
+        if samples == 0 {
             break;
         }
+        match s {
+            Ok(s) => {
+                if writer.write_sample(s).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                log::error(
+                    Class::Io,
+                    format!("Error reading file {}: {e}", input.as_ref().display()),
+                );
+                break;
+            }
+        }
+        samples -= 1;
+        pb.inc(1);
     }
 
     let samples_processed = pb.position();
+    let frames = samples_processed / channels;
+    let mut provenance = Provenance {
+        requested_start: match start {
+            Position::Time(nanos) => Some(nanos),
+            Position::Sample(_) => None,
+        },
+        ..Default::default()
+    };
+    provenance.read(
+        input.as_ref(),
+        start_frame as u64,
+        start_frame as u64 + frames,
+    );
+    let timing = if anchors.is_empty() {
+        Timing::default()
+    } else {
+        let start_nanos =
+            anchors[0].1 + ((start_frame as f64 - anchors[0].0) / rate * 1e9).round() as i64;
+        provenance.achieved_start = Some(start_nanos);
+        Timing {
+            start_nanos: Some(match start {
+                Position::Time(nanos) => nanos,
+                Position::Sample(_) => start_nanos,
+            }),
+            pps: pps
+                .iter()
+                .filter(|(frame, _)| *frame >= start_frame as f64)
+                .map(|(frame, nanos)| PpsMark {
+                    sample: *frame as u64 - start_frame as u64,
+                    nanos: *nanos,
+                })
+                .filter(|p| p.sample < frames)
+                .collect(),
+            uncertainty: None,
+        }
+    };
+    if writer.finalize(&timing, &provenance).is_err() {
+        pb.abandon();
+        return;
+    }
     pb.finish_with_message(format!("Samples processed: {samples_processed}"));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Format;
+    use crate::testing;
+
+    const NANOS: i64 = 1_718_186_580_000_000_000;
+
+    #[test]
+    fn timestamps_cuts_placed_by_sample() {
+        let _serial = testing::serial();
+        let dir = testing::dir();
+        let input = dir.path().join("in.wav");
+        // Three seconds of mono with a marker at the start of each
+        let mut samples = vec![0; 3 * 48000];
+        for second in 0..3 {
+            let at = 100 + second * 48000;
+            samples[at..at + 3]
+                .copy_from_slice(&testing::marker(NANOS + second as i64 * 1_000_000_000));
+        }
+        testing::wav(&input, 1, samples);
+
+        let output = dir.path().join("out.wav");
+        let options = Options {
+            timestamps: true,
+            ..Default::default()
+        };
+        let layout = PpsLayout::default();
+        let length = Length::Samples(100_000);
+        make_wav(
+            &output,
+            &input,
+            Position::Sample(1000),
+            length,
+            &layout,
+            &options,
+        );
+        let pps = std::fs::read_to_string(output.with_extension("pps.csv")).unwrap();
+        assert_eq!(
+            pps,
+            "sample,nanos\n47100,1718186581000000000\n95100,1718186582000000000\n"
+        );
+    }
+
+    #[test]
+    fn names_outputs_after_their_format() {
+        let _serial = testing::serial();
+        let dir = testing::dir();
+        let input = dir.path().join("in.wav");
+        testing::wav(&input, 2, 0..2000);
+        for format in [Format::Flac, Format::Npy] {
+            let options = Options {
+                format,
+                ..Default::default()
+            };
+            let output = dir.path().join("out.wav");
+            let (start, length) = (Position::Sample(0), Length::Samples(1000));
+            make_wav(
+                &output,
+                &input,
+                start,
+                length,
+                &PpsLayout::default(),
+                &options,
+            );
+            assert!(output.with_extension(format.extension()).exists());
+        }
+        assert!(!dir.path().join("out.wav").exists());
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, NaiveDateTime};
+
+use crate::log;
+
+/// Extensions of the files taken as recordings, compared case-insensitively
+const AUDIO_EXTENSIONS: [&str; 2] = ["wav", "wave"];
+/// Skipped files named in the warning, the rest are counted
+const SHOWN: usize = 5;
+
+/// How a recording's file name gives the time it starts at. A prefix without digits, such
+/// as `rec_`, is ignored.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum NamePattern {
+    /// Nanoseconds since the epoch, e.g. `1718186579700000000`
+    Nanos,
+    /// ISO 8601 date and time, UTC unless an offset is given, e.g. `2024-06-12T10:02:59.7Z`,
+    /// `2024-06-12T10-02-59Z` or `20240612T100259.700Z`
+    Iso8601,
+    /// `YYYYMMDD_HHMMSS` in UTC, e.g. `20240612_100259`
+    #[value(name = "datetime")]
+    DateTime,
+}
+
+/// The patterns tried, in order, when none are chosen
+pub const PATTERNS: [NamePattern; 3] = [
+    NamePattern::Nanos,
+    NamePattern::Iso8601,
+    NamePattern::DateTime,
+];
+
+const ISO_FORMATS: [&str; 3] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%dT%H-%M-%S%.f",
+    "%Y%m%dT%H%M%S%.f",
+];
+
+impl NamePattern {
+    /// UTC nanos of a file name stem
+    pub fn parse(self, stem: &str) -> Option<i64> {
+        let digits = stem.find(|c: char| c.is_ascii_digit())?;
+        [stem, &stem[digits..]]
+            .into_iter()
+            .find_map(|s| self.parse_exact(s))
+    }
+
+    fn parse_exact(self, s: &str) -> Option<i64> {
+        let naive = match self {
+            NamePattern::Nanos => {
+                return s
+                    .bytes()
+                    .all(|b| b.is_ascii_digit())
+                    .then(|| s.parse().ok())?
+            }
+            NamePattern::Iso8601 => {
+                let offset = ISO_FORMATS
+                    .iter()
+                    .find_map(|f| DateTime::parse_from_str(s, &format!("{f}%#z")).ok());
+                if let Some(t) = offset {
+                    return t.timestamp_nanos_opt();
+                }
+                let s = s.strip_suffix('Z').unwrap_or(s);
+                ISO_FORMATS
+                    .iter()
+                    .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())?
+            }
+            NamePattern::DateTime => NaiveDateTime::parse_from_str(s, "%Y%m%d_%H%M%S").ok()?,
+        };
+        naive.and_utc().timestamp_nanos_opt()
+    }
+}
+
+/// A recording found under an input directory
+pub struct Recording {
+    pub path: PathBuf,
+    /// UTC nanos its file name gives
+    pub nanos: i64,
+}
+
+/// Directories whose skipped files were already reported
+static REPORTED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Audio files in `dir` and its subdirectories whose names give a time by one of
+/// `patterns`, in time order. Other files are skipped and reported, once per directory.
+pub fn recordings(dir: &Path, patterns: &[NamePattern]) -> Vec<Recording> {
+    let mut recordings = Vec::new();
+    let mut skipped = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                skipped.push((dir, format!("unreadable directory: {e}")));
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // Symlinks are not followed into, so a link loop cannot recurse forever
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                dirs.push(path);
+                continue;
+            }
+            let audio = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| AUDIO_EXTENSIONS.iter().any(|a| a.eq_ignore_ascii_case(e)));
+            if !audio {
+                skipped.push((path, "not a recording".to_owned()));
+                continue;
+            }
+            let stem = path.file_stem().unwrap().to_string_lossy();
+            match patterns.iter().find_map(|p| p.parse(&stem)) {
+                Some(nanos) => recordings.push(Recording { path, nanos }),
+                None => skipped.push((path, "no time in the file name".to_owned())),
+            }
+        }
+    }
+    recordings.sort_unstable_by(|a, b| (a.nanos, &a.path).cmp(&(b.nanos, &b.path)));
+
+    if !skipped.is_empty() && REPORTED.lock().unwrap().insert(dir.to_path_buf()) {
+        skipped.sort_unstable();
+        for (path, reason) in &skipped {
+            log::debug(format!("Skipping {}: {reason}", path.display()));
+        }
+        let mut names = skipped
+            .iter()
+            .take(SHOWN)
+            .map(|(path, _)| match path.strip_prefix(dir) {
+                Ok(relative) if relative != Path::new("") => relative.display().to_string(),
+                _ => path.display().to_string(),
+            })
+            .collect::<Vec<_>>();
+        if skipped.len() > SHOWN {
+            names.push(format!("and {} more", skipped.len() - SHOWN));
+        }
+        log::warn(format!(
+            "Skipping {} file{} in {}, not timestamped recordings: {}",
+            skipped.len(),
+            if skipped.len() == 1 { "" } else { "s" },
+            dir.display(),
+            names.join(", ")
+        ));
+    }
+    recordings
+}
+
+/// Paths of the recordings under `dir` named by any of `patterns`, in time order
+pub fn waves(dir: &Path, patterns: &[NamePattern]) -> Vec<PathBuf> {
+    recordings(dir, patterns)
+        .into_iter()
+        .map(|r| r.path)
+        .collect()
+}
+
+/// The recording in `waves` called `name`, such as a clock record's file, wherever it is
+/// under the input dir
+pub fn find<'a>(waves: &'a [PathBuf], name: &str) -> Option<&'a PathBuf> {
+    waves
+        .iter()
+        .find(|w| w.file_name().is_some_and(|n| n == name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const NANOS: i64 = 1_718_186_579_700_000_000;
+
+    #[test]
+    fn parses_nanos() {
+        assert_eq!(NamePattern::Nanos.parse("1718186579700000000"), Some(NANOS));
+        assert_eq!(
+            NamePattern::Nanos.parse("rec_1718186579700000000"),
+            Some(NANOS)
+        );
+        assert_eq!(NamePattern::Nanos.parse("20240612T100259.700Z"), None);
+        assert_eq!(NamePattern::Nanos.parse("notes"), None);
+    }
+
+    #[test]
+    fn parses_iso8601() {
+        for stem in [
+            "2024-06-12T10:02:59.7Z",
+            "2024-06-12T10-02-59.700Z",
+            "20240612T100259.700Z",
+            "20240612T100259.7",
+            "rec_2024-06-12T12:02:59.7+02:00",
+        ] {
+            assert_eq!(NamePattern::Iso8601.parse(stem), Some(NANOS), "{stem}");
+        }
+        assert_eq!(NamePattern::Iso8601.parse("20240612_100259"), None);
+        assert_eq!(NamePattern::Iso8601.parse("1718186579700000000"), None);
+    }
+
+    #[test]
+    fn parses_date_time() {
+        assert_eq!(
+            NamePattern::DateTime.parse("REC_20240612_100259"),
+            Some(NANOS - 700_000_000)
+        );
+        assert_eq!(NamePattern::DateTime.parse("20240612T100259Z"), None);
+    }
+
+    #[test]
+    fn finds_recordings_in_subdirs_in_time_order() {
+        let tmp = testing::dir();
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("day2")).unwrap();
+        for name in [
+            "day2/1718186589700000000.WAV",
+            "20240612T100259.700Z.wav",
+            "1718186599700000000.flac",
+            "notes.wav",
+        ] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir, dir.join("day2/loop")).unwrap();
+
+        let found = recordings(dir, &PATTERNS);
+        let names = found
+            .iter()
+            .map(|r| r.path.strip_prefix(dir).unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["20240612T100259.700Z.wav", "day2/1718186589700000000.WAV"]
+        );
+        assert_eq!(found[0].nanos, NANOS);
+        assert_eq!(recordings(dir, &[NamePattern::Nanos]).len(), 1);
+    }
+}
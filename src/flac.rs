@@ -0,0 +1,206 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, StreamInfo};
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Context, Fill, FrameBuf};
+
+use crate::log;
+
+const BLOCK_SIZE: usize = 4096;
+const MAX_BITS: u16 = 24;
+const STREAM_INFO: u8 = 0;
+const VORBIS_COMMENT: u8 = 4;
+const STREAM_INFO_OFFSET: u64 = 4;
+
+/// Streaming FLAC encoder with the same write_sample/finalize shape as `hound::WavWriter`.
+///
+/// FLAC caps samples at 24 bits, so wider input is shifted down to 24 bits.
+pub struct FlacWriter {
+    out: BufWriter<File>,
+    config: Verified<flacenc::config::Encoder>,
+    stream_info: StreamInfo,
+    framebuf: FrameBuf,
+    context: Context,
+    pending: Vec<i32>,
+    channels: usize,
+    shift: u16,
+    frames: usize,
+}
+
+impl FlacWriter {
+    pub fn create<P: std::convert::AsRef<Path>>(
+        path: P,
+        spec: hound::WavSpec,
+        comments: &[(String, String)],
+    ) -> io::Result<Self> {
+        let bits = spec.bits_per_sample.min(MAX_BITS);
+        let shift = spec.bits_per_sample - bits;
+        if shift > 0 {
+            log::warn(format!(
+                "FLAC supports at most {MAX_BITS} bits per sample, downconverting {}-bit samples (lowest {shift} bits discarded)",
+                spec.bits_per_sample
+            ));
+        }
+        let channels = spec.channels as usize;
+        let stream_info =
+            StreamInfo::new(spec.sample_rate as usize, channels, bits as usize).unwrap();
+
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(b"fLaC")?;
+        write_block(
+            &mut out,
+            STREAM_INFO,
+            false,
+            &stream_info_bytes(&stream_info),
+        )?;
+        write_block(&mut out, VORBIS_COMMENT, true, &vorbis_comment(comments))?;
+
+        Ok(Self {
+            out,
+            config: flacenc::config::Encoder::default().into_verified().unwrap(),
+            stream_info,
+            framebuf: FrameBuf::with_size(channels, BLOCK_SIZE).unwrap(),
+            context: Context::new(bits as usize, channels),
+            pending: Vec::with_capacity(BLOCK_SIZE * channels),
+            channels,
+            shift,
+            frames: 0,
+        })
+    }
+
+    pub fn write_sample(&mut self, sample: i32) -> io::Result<()> {
+        self.pending.push(sample >> self.shift);
+        if self.pending.len() == BLOCK_SIZE * self.channels {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        // A trailing partial frame is dropped, as hound does for incomplete frames.
+        let whole = self.pending.len() - self.pending.len() % self.channels;
+        if whole == 0 {
+            return Ok(());
+        }
+        let block = &self.pending[..whole];
+        self.framebuf.fill_interleaved(block).unwrap();
+        self.context.fill_interleaved(block).unwrap();
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &self.framebuf,
+            self.frames,
+            &self.stream_info,
+        )
+        .unwrap();
+        self.stream_info.update_frame_info(&frame);
+
+        let mut sink = ByteSink::new();
+        frame.write(&mut sink).unwrap();
+        self.out.write_all(sink.as_slice())?;
+
+        self.frames += 1;
+        self.pending.clear();
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.stream_info.set_md5_digest(&self.context.md5_digest());
+        // The last frame may be short, which is allowed and not reflected in min_block_size.
+        self.stream_info
+            .set_block_sizes(BLOCK_SIZE, BLOCK_SIZE)
+            .unwrap();
+        self.out.seek(SeekFrom::Start(STREAM_INFO_OFFSET))?;
+        write_block(
+            &mut self.out,
+            STREAM_INFO,
+            false,
+            &stream_info_bytes(&self.stream_info),
+        )?;
+        self.out.flush()
+    }
+}
+
+fn stream_info_bytes(stream_info: &StreamInfo) -> Vec<u8> {
+    let mut sink = ByteSink::new();
+    stream_info.write(&mut sink).unwrap();
+    sink.into_inner()
+}
+
+fn write_block<W: Write>(
+    out: &mut W,
+    block_type: u8,
+    is_last: bool,
+    data: &[u8],
+) -> io::Result<()> {
+    let header = block_type | if is_last { 0x80 } else { 0x00 };
+    let len = (data.len() as u32).to_be_bytes();
+    out.write_all(&[header, len[1], len[2], len[3]])?;
+    out.write_all(data)
+}
+
+fn vorbis_comment(comments: &[(String, String)]) -> Vec<u8> {
+    let vendor = concat!("wave ", env!("CARGO_PKG_VERSION"));
+    let mut data = Vec::new();
+    data.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    data.extend_from_slice(vendor.as_bytes());
+    data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (key, value) in comments {
+        let comment = format!("{}={value}", key.to_uppercase());
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use md5::{Digest, Md5};
+
+    #[test]
+    fn decodes_to_the_samples_written() {
+        let dir = testing::dir();
+        let path = dir.path().join("cut.flac");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Int,
+        };
+        // Two whole blocks and a short one, with a sign change and values using all 24 bits
+        let samples = (0..2 * (2 * BLOCK_SIZE as i32 + 100))
+            .map(|i| (i * 7919 % 0x100_0000 - 0x80_0000) << 8)
+            .collect::<Vec<_>>();
+        let comments = [("label".to_owned(), "hover".to_owned())];
+        let mut writer = FlacWriter::create(&path, spec, &comments).unwrap();
+        for &sample in &samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut reader = claxon::FlacReader::open(&path).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!((info.sample_rate, info.channels), (48000, 2));
+        assert_eq!(info.bits_per_sample, MAX_BITS as u32);
+        assert_eq!(info.samples, Some(2 * BLOCK_SIZE as u64 + 100));
+        let block_sizes = (info.min_block_size, info.max_block_size);
+        assert_eq!(block_sizes, (BLOCK_SIZE as u16, BLOCK_SIZE as u16));
+        // Wider samples are shifted down to 24 bits
+        let expected = samples.iter().map(|s| s >> 8).collect::<Vec<_>>();
+        let md5 = Md5::digest(
+            expected
+                .iter()
+                .flat_map(|s| s.to_le_bytes()[..3].to_vec())
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(info.md5sum, md5.as_slice());
+        assert_eq!(reader.get_tag("LABEL").collect::<Vec<_>>(), ["hover"]);
+        let decoded = reader.samples().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(decoded, expected);
+    }
+}
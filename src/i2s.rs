@@ -1,14 +1,20 @@
-use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
 
 use circular_buffer::CircularBuffer;
-use indicatif::{ProgressBar, ProgressStyle};
-
-use crate::Record;
+use indicatif::ProgressStyle;
+
+use crate::clock;
+use crate::discover::{self, NamePattern};
+use crate::log::{self, Class};
+use crate::manifest::Provenance;
+use crate::name::Fields;
+use crate::output::{Options, Writer};
+use crate::profile::DeviceProfile;
+use crate::reader::{Event, TimedSampleReader};
+use crate::timing::{uncertainty_message, PpsTracker, Timing};
 
 //const CHANNELS: u32 = 4;
-const FREQ: f64 = 192000.0;
 const BUF_SIZE: usize = 33;
 const BUF_SIZE_INNER: usize = 8;
 const MID: usize = BUF_SIZE_INNER / 2;
@@ -20,31 +26,64 @@ struct CircularI2S {
     new_row: [i32; BUF_SIZE_INNER],
     //index: usize,
     inner_index: usize,
-    files: [hound::WavWriter<BufWriter<File>>; BUF_SIZE_INNER + 1],
+    files: [Writer; BUF_SIZE_INNER + 1],
+}
+
+/// Output of each beam of microphone row `num`
+fn beam_paths(path: &Path, num: u8, options: &Options) -> Vec<PathBuf> {
+    let ext = options.format.extension();
+    let template = options
+        .name_template
+        .as_ref()
+        .filter(|t| ["array", "beam", "beam_angle"].iter().any(|f| t.uses(f)));
+    let dir = path.parent().unwrap_or(Path::new(""));
+    (0..=BUF_SIZE_INNER)
+        .map(|i| match template {
+            Some(template) => {
+                let fields = Fields {
+                    array: Some(num),
+                    beam: Some(i),
+                    // Rows are delayed by this many samples against each other
+                    beam_angle: Some(MID as i64 - i as i64),
+                    ..options.fields.clone()
+                };
+                let name = template.render(&fields);
+                dir.join(format!("{name}.{ext}"))
+            }
+            None => PathBuf::from(format!("{}_{num}_{i}.{ext}", path.display())),
+        })
+        .collect()
+}
+
+/// Every file `make_wav` writes for `output`
+pub fn outputs(output: &Path, options: &Options) -> Vec<PathBuf> {
+    [1, 2]
+        .into_iter()
+        .flat_map(|num| beam_paths(output, num, options))
+        .collect()
 }
 
 impl CircularI2S {
-    fn new<P: std::convert::AsRef<Path>>(path: P, num: u8) -> Self {
-        let paths: [String; BUF_SIZE_INNER + 1] = (0..=BUF_SIZE_INNER)
-            .map(|i| format!("{}_{num}_{i}.wav", path.as_ref().display()))
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
-        let spec = hound::WavSpec {
-            channels: 1,
-            sample_rate: 48000,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Int,
-        };
-        let files: [hound::WavWriter<BufWriter<File>>; BUF_SIZE_INNER + 1] = paths
-            .into_iter()
-            .map(|p| hound::WavWriter::create(p, spec).unwrap())
-            .collect::<Vec<_>>()
+    fn new<P: std::convert::AsRef<Path>>(
+        path: P,
+        num: u8,
+        spec: hound::WavSpec,
+        options: &Options,
+    ) -> io::Result<Self> {
+        let mut files = Vec::with_capacity(BUF_SIZE_INNER + 1);
+        for path in beam_paths(path.as_ref(), num, options) {
+            match Writer::create(path, spec, options) {
+                Ok(writer) => files.push(writer),
+                Err(e) => {
+                    files.into_iter().for_each(Writer::discard);
+                    return Err(e);
+                }
+            }
+        }
+        let files: [Writer; BUF_SIZE_INNER + 1] = files
             .try_into()
-            .unwrap_or_else(|_| {
-                panic!("Failed to create hound::WavWriter array (probably wrong path)")
-            });
-        Self {
+            .unwrap_or_else(|_| panic!("Expected one output per beam"));
+        Ok(Self {
             _size: BUF_SIZE,
             _inner_size: BUF_SIZE_INNER,
             buf: CircularBuffer::new(),
@@ -52,7 +91,7 @@ impl CircularI2S {
             //index: 0,
             inner_index: 0,
             files,
-        }
+        })
     }
 
     fn increment_index(&mut self) -> bool {
@@ -85,77 +124,81 @@ impl CircularI2S {
     //    self.buf[i][j]
     //}
 
-    fn compute_samples(&mut self) {
+    fn compute_samples(&mut self) -> io::Result<()> {
         if self.buf.is_full() {
             for i in 0..=BUF_SIZE_INNER {
-                let mut j = MID * i;
-                let step = MID - i;
-
-                let mut sample = 0;
-
-                for k in 0..8 {
-                    sample += self.buf[j][k] as i64;
-                    j += step;
-                }
-
-                let sample = (sample / 8) as i32;
-                self.files[i].write_sample(sample).unwrap();
+                self.files[i].write_sample(beam(&self.buf, i))?;
             }
         }
+        Ok(())
+    }
+
+    /// Finishes every beam, returning the first failure
+    fn finalize(self, timing: &Timing, provenance: &Provenance) -> io::Result<()> {
+        self.files
+            .into_iter()
+            .map(|w| w.finalize(timing, provenance))
+            .fold(Ok(()), Result::and)
     }
 
-    fn finalize(self) {
-        self.files.into_iter().for_each(|w| w.finalize().unwrap());
+    fn discard(self) {
+        self.files.into_iter().for_each(Writer::discard);
     }
 }
 
+/// Sample of beam `i`: the mean of the microphones of a row, each delayed `MID - i` rows more
+/// than the one before
+fn beam(buf: &CircularBuffer<BUF_SIZE, [i32; BUF_SIZE_INNER]>, i: usize) -> i32 {
+    let mut j = (MID * i) as isize;
+    // Beams past the middle step backwards through the rows
+    let step = MID as isize - i as isize;
+
+    let mut sample = 0;
+
+    for k in 0..8 {
+        sample += buf[j as usize][k] as i64;
+        j += step;
+    }
+
+    (sample / 8) as i32
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn make_wav<P: std::convert::AsRef<Path>>(
     output: P,
     input_dir: P,
+    patterns: &[NamePattern],
     clock: P,
     start: Option<i64>,
     samples: Option<u64>,
+    profile: &DeviceProfile,
+    options: &Options,
 ) {
-    let mut bufs = [
-        CircularI2S::new(output.as_ref(), 1),
-        CircularI2S::new(output.as_ref(), 2),
-    ];
-
-    let mut waves = std::fs::read_dir(input_dir.as_ref())
-        .unwrap()
-        .flat_map(|f| f.map(|e| e.path()))
-        .collect::<Vec<_>>();
-    waves.sort_unstable();
+    let spec = profile.output_spec();
+    let freq = profile.rate();
+    let out_freq = spec.sample_rate as f64;
+    // The highest tag bit selects the row, the rest the microphone within it
+    let row_bit = 1u32 << (profile.tag_bits - 1);
+    let mic_mask = row_bit - 1;
 
     let clock_start_nanos_str = clock.as_ref().file_stem().unwrap().to_str().unwrap();
-
-    let mut wav_iter = waves.iter().peekable();
-    while let Some(wav) = wav_iter.peek() {
-        if wav.file_stem().unwrap().to_str().unwrap() == clock_start_nanos_str {
-            break;
-        }
-        wav_iter.next();
-    }
-
-    if wav_iter.peek().is_none() {
-        eprintln!("Clock start not found");
+    if !discover::waves(input_dir.as_ref(), patterns)
+        .iter()
+        .any(|w| w.file_stem().is_some_and(|s| s == clock_start_nanos_str))
+    {
+        log::error(Class::Input, "Clock start not found");
         return;
     }
 
-    let mut reader = csv::Reader::from_path(clock).unwrap();
-
-    let records = reader
-        .deserialize()
-        // .map(|r| r.unwrap())
-        .flatten()
-        .collect::<Vec<Record>>();
+    let records = clock::read_records(&clock);
 
     if records.is_empty() {
-        eprintln!("Failed to read clock csv");
+        log::error(Class::Input, "Failed to read clock csv");
         return;
     }
 
     let n_records = records.len();
+    let rates = clock::interval_rates(&records);
 
     // let mut start_file = records[0].file.clone();
     // let mut file_start_sample = 0;
@@ -175,46 +218,42 @@ pub fn make_wav<P: std::convert::AsRef<Path>>(
     // let start_file = input_dir.as_ref().join(start_file);
     // let mut file_start_sample = file_start_sample as u32;
 
+    let mut provenance = Provenance {
+        clocks: vec![clock.as_ref().display().to_string()],
+        requested_start: start,
+        ..Default::default()
+    };
     let mut start_file = records[0].file.clone();
     let mut file_start_sample = 0;
     if let Some(start) = start {
-        if records[0].time - (records[0].file_sample as f64 / FREQ * 1e9).round() as i64 > start
-            || records[n_records - 1].time < start
-        {
-            file_start_sample = -1;
-        } else {
-            let mut diff = i64::MAX;
-            for r in records.iter() {
-                let r_diff = (r.time - start).abs();
-                if r_diff < diff {
-                    diff = r_diff;
-                    start_file = r.file.clone();
-                    let r_diff = start - r.time;
-                    let sample_diff = (r_diff as f64 * FREQ / 1e9).round() as i64;
-                    file_start_sample = (r.file_sample as i64 + sample_diff).max(0);
-                }
+        match clock::locate(&records, &rates, start, freq) {
+            Some(fix) if fix.sample >= 0.0 => {
+                let r = &records[fix.record];
+                start_file = r.file.clone();
+                let exact = r.file_sample as f64 + fix.sample - r.sample as f64;
+                file_start_sample = exact.round().max(0.0) as i64;
+                provenance.achieved_start =
+                    Some(start + ((file_start_sample as f64 - exact) / freq * 1e9).round() as i64);
             }
+            _ => file_start_sample = -1,
         }
     }
     if file_start_sample == -1 {
-        eprintln!("Requested start not in audio data time range");
+        log::error(
+            Class::OutOfRange,
+            "Requested start not in audio data time range",
+        );
         return;
     }
     let start_file = input_dir.as_ref().join(start_file);
-    let mut file_start_sample = file_start_sample as u32;
-
-    while let Some(wav) = wav_iter.peek() {
-        if **wav == start_file {
-            break;
-        }
-        wav_iter.next();
-    }
+    let file_start_sample = file_start_sample as u32;
 
-    //let start_nanos = if let Some(start) = start {
-    //    start
-    //} else {
-    //    records[0].time - (records[0].sample as f64 / FREQ * 1e9).round() as i64
-    //};
+    let start_nanos = if let Some(start) = start {
+        start
+    } else {
+        records[0].time - (records[0].file_sample as f64 / freq * 1e9).round() as i64
+    };
+    provenance.achieved_start.get_or_insert(start_nanos);
     let end_file = records[n_records - 1].file.clone();
     let end_file = input_dir.as_ref().join(end_file);
 
@@ -224,8 +263,10 @@ pub fn make_wav<P: std::convert::AsRef<Path>>(
         let samples = records[n_records - 1].sample;
         [samples; 2]
     };
+    let requested = samples[0];
+    let mut tracker = PpsTracker::new(&records, out_freq / freq);
 
-    let pb = ProgressBar::new(samples[0] * 2);
+    let pb = log::progress_bar(samples[0] * 2);
     let t = (2.0 * samples[0] as f64).log10().ceil() as u64;
     pb.set_style(
         ProgressStyle::with_template(&format!(
@@ -237,69 +278,108 @@ pub fn make_wav<P: std::convert::AsRef<Path>>(
 
     // let mut med = Vec::new();
 
+    // Outputs are only created once the clock and start are known to be valid
+    let Ok(first) = CircularI2S::new(output.as_ref(), 1, spec, options) else {
+        return;
+    };
+    let second = match CircularI2S::new(output.as_ref(), 2, spec, options) {
+        Ok(second) => second,
+        Err(_) => {
+            first.discard();
+            return;
+        }
+    };
+    let mut bufs = [first, second];
+    let mut reader = TimedSampleReader::new(input_dir.as_ref(), patterns, freq, 1);
+    reader.seek(&start_file, file_start_sample, Some(&end_file), &records);
+    // Words are skipped until the first of row 1, microphone 1
     let mut start = true;
-    let mut end = false;
-    for wav in wav_iter {
-        let mut reader = match hound::WavReader::open(wav) {
-            Ok(r) => r,
-            Err(e) => {
-                eprintln!("Error reading file: {e}");
-                return;
+    let mut counter = 0;
+    while let Some(event) = reader.next_event() {
+        let sample = match event {
+            Event::Sample(sample) => sample,
+            Event::File { file, seek, .. } => {
+                // Beam output lags the rows read until the circular buffer is full
+                let rows = (requested - samples[0]) as i64;
+                let file_name = file.file_name().unwrap().to_str().unwrap();
+                tracker.file(file_name, seek, rows - (BUF_SIZE as i64 - 1));
+                counter = 0;
+                continue;
             }
+            Event::Session { .. } | Event::Gap { .. } => continue,
         };
-
+        // med.push(sample);
         if start {
-            if file_start_sample <= reader.duration() {
-                reader.seek(file_start_sample).unwrap();
-            } else {
-                file_start_sample -= reader.duration();
+            let mic = (sample as u32 & row_bit != 0) as usize;
+            let inner_index = (sample as u32 & mic_mask) as usize;
+            if mic != 1 || inner_index != 1 {
+                counter += 1;
+                if counter >= 32 {
+                    break;
+                }
                 continue;
             }
+            start = false;
         }
+        let mic = (sample as u32 & row_bit != 0) as usize;
 
-        let mut counter = 0;
-        for s in reader.samples::<i32>() {
-            let sample = s.unwrap();
-            // med.push(sample);
-            if start {
-                let mic = ((sample as u32 & 0b1000) >> 3) as usize;
-                let inner_index = (sample as u32 & 0b111) as usize;
-                if mic != 1 || inner_index != 1 {
-                    counter += 1;
-                    if counter >= 32 {
-                        end = true;
-                        break;
-                    }
-                    continue;
-                }
-                start = false;
-            }
-            let mic = ((sample as u32 & 0b1000) >> 3) as usize;
-
-            let inner_index = (sample as u32 & 0b111) as usize;
-
-            if samples[mic] > 0 && bufs[mic].set_inner(sample, inner_index) {
-                bufs[mic].compute_samples();
-                samples[mic] -= 1;
-                pb.inc(1);
-            }
+        let inner_index = (sample as u32 & mic_mask) as usize;
 
-            if samples.iter().all(|x| *x == 0) {
-                end = true;
+        if samples[mic] > 0 && bufs[mic].set_inner(sample, inner_index) {
+            // A failed write is logged and discards every beam on finalizing
+            if bufs[mic].compute_samples().is_err() {
                 break;
             }
+            samples[mic] -= 1;
+            pb.inc(1);
         }
 
-        if end || *wav == end_file {
+        if samples.iter().all(|x| *x == 0) {
             break;
         }
     }
-    for b in bufs {
-        b.finalize();
+    let (read, _) = reader.finish();
+    provenance.sources = read.sources;
+    let written = (requested - samples[0]).saturating_sub(BUF_SIZE as u64 - 1);
+    let end_nanos = start_nanos + (written as f64 / out_freq * 1e9).round() as i64;
+    let uncertainty = clock::uncertainty(&records, &rates, start_nanos, end_nanos, freq);
+    let timing = Timing {
+        start_nanos: Some(start_nanos),
+        pps: tracker.finish(written),
+        uncertainty: Some(uncertainty),
+    };
+    let finalized = bufs
+        .into_iter()
+        .map(|b| b.finalize(&timing, &provenance))
+        .fold(Ok(()), Result::and);
+    if finalized.is_err() {
+        pb.abandon();
+        return;
     }
     let samples_processed = pb.position();
-    pb.finish_with_message(format!("Samples processed: {samples_processed}"));
+    let message = uncertainty_message(Some(uncertainty), options.max_uncertainty);
+    pb.finish_with_message(format!("Samples processed: {samples_processed}{message}"));
     // med.sort_unstable();
     // let med = med[med.len() / 2];
     // println!("median: {med}");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_each_microphone_by_the_beam_angle() {
+        let mut buf = CircularBuffer::new();
+        // Every microphone of row r hears 8 * r, so a beam averages the rows it picks
+        for row in 0..BUF_SIZE as i32 {
+            buf.push_back([8 * row; BUF_SIZE_INNER]);
+        }
+        // Rows 0, 4, .., 28
+        assert_eq!(beam(&buf, 0), 8 * 14);
+        // Row 16 for every microphone
+        assert_eq!(beam(&buf, MID), 8 * 16);
+        // Rows 32, 28, .., 4, stepping backwards
+        assert_eq!(beam(&buf, BUF_SIZE_INNER), 8 * 18);
+    }
+}
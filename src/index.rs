@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::log;
+use crate::pps::{get_pps, Pps, PpsLayout};
+use crate::repair;
+
+/// Size and modification time, used to tell whether a cached entry is still valid
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct Stamp {
+    size: u64,
+    mtime: u64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: meta.len(),
+            mtime: mtime.as_nanos() as u64,
+        })
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct FileEntry {
+    stamp: Stamp,
+    duration: u32,
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    /// PPS layout the markers were decoded with, and the (sample, nanos) pairs found
+    pps: Option<(PpsLayout, Vec<(u32, i64)>)>,
+}
+
+/// Cache of per-file durations, specs and PPS positions for one input directory, persisted
+/// as `<input_dir>.wave-index` next to it.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct Index {
+    /// None when the input dir cannot be resolved, so the index is never saved
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// The input dir as given, which file paths are relative to
+    #[serde(skip)]
+    dir: PathBuf,
+    #[serde(skip)]
+    dirty: bool,
+    /// Keyed by the path relative to the input dir, as files in different subdirectories
+    /// may share a name
+    files: BTreeMap<String, FileEntry>,
+}
+
+impl Index {
+    pub fn open<P: std::convert::AsRef<Path>>(input_dir: P) -> Self {
+        let path = input_dir.as_ref().canonicalize().ok().and_then(|dir| {
+            let name = dir.file_name()?.to_string_lossy().into_owned();
+            Some(dir.parent()?.join(format!("{name}.wave-index")))
+        });
+        let mut index = path
+            .as_ref()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|f| serde_json::from_reader::<_, Index>(BufReader::new(f)).ok())
+            .unwrap_or_default();
+        index.path = path;
+        index.dir = input_dir.as_ref().to_path_buf();
+        index
+    }
+
+    /// Writes the index back if anything was added. It is written under a temporary name
+    /// and renamed into place, so an interrupted run never leaves a truncated index.
+    pub fn save(&self) {
+        let Some(path) = self.path.as_ref().filter(|_| self.dirty) else {
+            return;
+        };
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let written = File::create(&tmp).and_then(|f| {
+            let mut out = BufWriter::new(f);
+            serde_json::to_writer(&mut out, self)?;
+            out.flush()
+        });
+        if let Err(e) = written.and_then(|()| std::fs::rename(&tmp, path)) {
+            log::warn(format!("Writing index {}: {e}", path.display()));
+            let _ = std::fs::remove_file(&tmp);
+        }
+    }
+
+    fn key(&self, path: &Path) -> String {
+        path.strip_prefix(&self.dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn entry(&mut self, path: &Path) -> Option<&mut FileEntry> {
+        let stamp = Stamp::of(path)?;
+        let key = self.key(path);
+        if self.files.get(&key).is_none_or(|e| e.stamp != stamp) {
+            let reader = repair::open(path).ok()?;
+            let spec = reader.spec();
+            self.files.insert(
+                key.clone(),
+                FileEntry {
+                    stamp,
+                    duration: reader.duration(),
+                    channels: spec.channels,
+                    sample_rate: spec.sample_rate,
+                    bits_per_sample: spec.bits_per_sample,
+                    pps: None,
+                },
+            );
+            self.dirty = true;
+        }
+        self.files.get_mut(&key)
+    }
+
+    /// Duration in frames and spec of a wav file
+    pub fn wav_info(&mut self, path: &Path) -> Option<(u32, hound::WavSpec)> {
+        let e = self.entry(path)?;
+        let spec = hound::WavSpec {
+            channels: e.channels,
+            sample_rate: e.sample_rate,
+            bits_per_sample: e.bits_per_sample,
+            sample_format: hound::SampleFormat::Int,
+        };
+        Some((e.duration, spec))
+    }
+
+    /// PPS markers of a wav file, decoded with `layout` unless already cached
+    pub fn pps(&mut self, path: &Path, layout: &PpsLayout) -> Vec<Pps> {
+        let Some(entry) = self.entry(path) else {
+            return Vec::new();
+        };
+        if let Some((decoded_with, markers)) = &entry.pps {
+            if decoded_with == layout {
+                return markers
+                    .iter()
+                    .map(|(sample, nanos)| Pps {
+                        nanos: *nanos,
+                        sample: *sample,
+                        file: path.to_path_buf(),
+                    })
+                    .collect();
+            }
+        }
+        let pps_vec = get_pps(path, layout);
+        entry.pps = Some((
+            layout.clone(),
+            pps_vec.iter().map(|p| (p.sample, p.nanos)).collect(),
+        ));
+        self.dirty = true;
+        pps_vec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn keeps_same_named_files_apart() {
+        let tmp = testing::dir();
+        let dir = tmp.path().join("in");
+        for (day, frames) in [("day1", 10), ("day2", 20)] {
+            std::fs::create_dir_all(dir.join(day)).unwrap();
+            testing::wav(&dir.join(day).join("rec.wav"), 1, 0..frames);
+        }
+        let durations = |index: &mut Index| {
+            ["day1", "day2"].map(|day| index.wav_info(&dir.join(day).join("rec.wav")).unwrap().0)
+        };
+
+        let mut index = Index::open(&dir);
+        assert_eq!(durations(&mut index), [10, 20]);
+        index.save();
+        let names = std::fs::read_dir(tmp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(names, ["in".to_owned(), "in.wave-index".to_owned()].into());
+
+        let mut index = Index::open(&dir);
+        assert_eq!(index.files.len(), 2);
+        assert_eq!(durations(&mut index), [10, 20]);
+        assert!(!index.dirty);
+    }
+}
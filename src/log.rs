@@ -0,0 +1,211 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use indicatif::ProgressBar;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// What kind of failure an error is, which decides the exit code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    /// Bad arguments, configs, cuts files or recordings
+    Input,
+    /// A cut outside the recorded time range
+    OutOfRange,
+    /// Reading or writing files failed
+    Io,
+}
+
+impl Class {
+    const ALL: [Class; 3] = [Class::Input, Class::OutOfRange, Class::Io];
+
+    fn name(self) -> &'static str {
+        match self {
+            Class::Input => "input",
+            Class::OutOfRange => "out-of-range",
+            Class::Io => "io",
+        }
+    }
+
+    /// Exit code when this is the most serious error of a run: 2 for input errors, like
+    /// invalid arguments, 3 for out-of-range cuts and 4 for I/O failures
+    pub fn code(self) -> i32 {
+        match self {
+            Class::Input => 2,
+            Class::OutOfRange => 3,
+            Class::Io => 4,
+        }
+    }
+}
+
+/// How the job for one output of a batch ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Written,
+    Skipped,
+    Failed,
+}
+
+struct State {
+    level: Level,
+    quiet: bool,
+    /// JSON lines of every message at or above `level`
+    file: Option<BufWriter<File>>,
+    /// Errors by class
+    errors: [usize; 3],
+    /// Outputs written, skipped and failed
+    outcomes: [usize; 3],
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    level: Level::Info,
+    quiet: false,
+    file: None,
+    errors: [0; 3],
+    outcomes: [0; 3],
+});
+
+/// Sets the level, whether progress bars are hidden and the file to append JSON lines to
+pub fn init(level: Level, quiet: bool, file: Option<&Path>) -> Result<(), String> {
+    let file = file
+        .map(|path| {
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(BufWriter::new)
+                .map_err(|e| format!("Opening log file {}: {e}", path.display()))
+        })
+        .transpose()?;
+    let mut state = STATE.lock().unwrap();
+    state.level = level;
+    state.quiet = quiet;
+    state.file = file;
+    Ok(())
+}
+
+fn log(level: Level, class: Option<Class>, message: &dyn Display) {
+    let mut state = STATE.lock().unwrap();
+    if let Some(class) = class {
+        state.errors[class as usize] += 1;
+    }
+    if level > state.level {
+        return;
+    }
+    match level {
+        Level::Info => eprintln!("{message}"),
+        _ => eprintln!("{}: {message}", level.name()),
+    }
+    if let Some(file) = &mut state.file {
+        let line = serde_json::json!({
+            "time": chrono::Utc::now().to_rfc3339(),
+            "level": level.name(),
+            "class": class.map(Class::name),
+            "message": message.to_string(),
+        });
+        if let Err(e) = writeln!(file, "{line}").and_then(|_| file.flush()) {
+            eprintln!("Writing log file: {e}");
+            state.file = None;
+        }
+    }
+}
+
+pub fn error(class: Class, message: impl Display) {
+    log(Level::Error, Some(class), &message);
+}
+
+pub fn warn(message: impl Display) {
+    log(Level::Warn, None, &message);
+}
+
+pub fn info(message: impl Display) {
+    log(Level::Info, None, &message);
+}
+
+pub fn debug(message: impl Display) {
+    log(Level::Debug, None, &message);
+}
+
+/// Errors logged so far
+pub fn errors() -> usize {
+    STATE.lock().unwrap().errors.iter().sum()
+}
+
+/// Counts an output of a batch towards the summary
+pub fn outcome(outcome: Outcome) {
+    STATE.lock().unwrap().outcomes[outcome as usize] += 1;
+}
+
+/// A progress bar of `len` steps, hidden in quiet mode
+pub fn progress_bar(len: u64) -> ProgressBar {
+    if STATE.lock().unwrap().quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(len)
+    }
+}
+
+/// Logs the batch summary, if any outputs were counted, and returns the exit code: 0, or
+/// that of the most serious class of error logged, I/O before input before out-of-range
+pub fn finish() -> i32 {
+    let (errors, outcomes) = {
+        let state = STATE.lock().unwrap();
+        (state.errors, state.outcomes)
+    };
+    if outcomes.iter().sum::<usize>() > 0 {
+        let [written, skipped, failed] = outcomes;
+        let errors = Class::ALL
+            .iter()
+            .zip(errors)
+            .map(|(class, n)| format!("{n} {}", class.name()))
+            .collect::<Vec<_>>();
+        info(format!(
+            "Summary: {written} written, {skipped} skipped, {failed} failed; errors: {}",
+            errors.join(", ")
+        ));
+    }
+    exit_code(errors)
+}
+
+fn exit_code(errors: [usize; 3]) -> i32 {
+    [Class::Io, Class::Input, Class::OutOfRange]
+        .into_iter()
+        .find(|class| errors[*class as usize] > 0)
+        .map_or(0, Class::code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exits_with_the_most_serious_class() {
+        assert_eq!(exit_code([0; 3]), 0);
+        assert_eq!(exit_code([0, 5, 0]), Class::OutOfRange.code());
+        assert_eq!(exit_code([1, 5, 0]), Class::Input.code());
+        assert_eq!(exit_code([1, 5, 1]), Class::Io.code());
+        assert_eq!((Class::Input.code(), Class::OutOfRange.code()), (2, 3));
+        assert_eq!(Class::Io.code(), 4);
+    }
+}
@@ -3,12 +3,34 @@ use chrono::DateTime;
 use clap::{Parser, Subcommand};
 
 use self::concat::concat;
+use self::log::{Class, Outcome};
+use self::output::{Format, Options};
 
+mod clock;
 mod concat;
+mod config;
+mod discover;
+mod flac;
 mod i2s;
-//mod pps;
+mod index;
+mod log;
+mod manifest;
+mod name;
+mod nmea;
+mod npy;
+mod output;
+mod plan;
+mod pps;
+mod profile;
+mod reader;
+mod repair;
+mod resample;
+#[cfg(test)]
+mod testing;
 // mod concat_flights;
 mod cut_one;
+mod timing;
+mod track;
 mod umc;
 
 #[derive(Parser)]
@@ -17,6 +39,15 @@ mod umc;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Least severe messages to report
+    #[arg(long, value_enum, global = true, default_value_t)]
+    log_level: log::Level,
+    /// Also append messages as JSON lines to this file
+    #[arg(long, global = true)]
+    log_file: Option<String>,
+    /// Hide progress bars, e.g. when output is not a terminal
+    #[arg(short, long, global = true)]
+    quiet: bool,
 }
 
 #[derive(Subcommand)]
@@ -27,6 +58,15 @@ enum Commands {
     Cut(Args),
     /// Cut one file
     CutOne(CutOneArgs),
+    /// Builds a clock csv from NMEA and PPS edge logs, for recorders without PPS markers
+    ImportClock(ImportClockArgs),
+    /// Recovers wav files whose header sizes are wrong after a power loss
+    Repair(RepairArgs),
+    /// Generates cuts files
+    Cuts {
+        #[command(subcommand)]
+        command: CutsCommands,
+    },
     // ConcatCutsFlights(FlightsArgs),
 }
 
@@ -47,11 +87,103 @@ struct CutOneArgs {
     #[arg(short, long)]
     input: String,
     /// Start sample
-    #[arg(short, long)]
-    start: u32,
+    #[arg(short, long, required_unless_present = "start_time")]
+    start: Option<u32>,
+    /// Start time as RFC 3339 or nanos from epoch, located using the file's PPS markers
+    #[arg(long, value_parser = clock::parse_time, conflicts_with = "start")]
+    start_time: Option<i64>,
     /// Number of samples to write
+    #[arg(short = 'n', long, required_unless_present_any = ["duration", "end_time"])]
+    samples: Option<u64>,
+    /// Duration to write, e.g. 90s, 1.5m, 250ms
+    #[arg(long, value_parser = clock::parse_duration, conflicts_with = "samples")]
+    duration: Option<i64>,
+    /// End time as RFC 3339 or nanos from epoch, located using the file's PPS markers
+    #[arg(long, value_parser = clock::parse_time, conflicts_with_all = ["samples", "duration"])]
+    end_time: Option<i64>,
+    /// Output audio format
+    #[arg(long, value_enum, default_value_t)]
+    format: Format,
+    /// Output name without extension, placed next to --output; {name} is its stem and
+    /// {start} is --start-time
+    #[arg(long, value_parser = name::Template::parse)]
+    name_template: Option<name::Template>,
+    /// Write a .pps.csv sidecar with the UTC time of every PPS in the output
+    #[arg(long)]
+    timestamps: bool,
+    #[command(flatten)]
+    pps: pps::PpsLayout,
+}
+
+#[derive(Subcommand)]
+enum CutsCommands {
+    /// Cuts for the periods the drone spends in each distance band around each module
+    FromTrack(FromTrackArgs),
+}
+
+#[derive(clap::Args)]
+struct FromTrackArgs {
+    /// Campaign config (wave.toml) giving the module positions and the flight's track
+    #[arg(long)]
+    config: Option<String>,
+    /// GPX track, or csv with columns time (RFC 3339 or nanos), lat, lon, alt
+    #[arg(short, long, required_unless_present = "config")]
+    track: Option<String>,
+    /// Csv of module positions with columns module, lat, lon, alt
+    #[arg(long, required_unless_present = "config")]
+    modules: Option<String>,
+    /// Band edges in metres, e.g. 0,100,200 for the bands 0-100 m and 100-200 m
+    #[arg(long, value_delimiter = ',', required = true)]
+    ranges: Vec<f64>,
+    /// Flight written to every cut
+    #[arg(short, long)]
+    flight: String,
+    /// Drop stretches shorter than this
+    #[arg(long, value_parser = clock::parse_duration, default_value = "1s")]
+    min_duration: i64,
+    /// Path to output cuts csv, stdout when missing
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct ImportClockArgs {
+    /// Path to input directory containing wav files with names being numbers of nanoseconds since unix epoch
     #[arg(short, long)]
-    samples: u64,
+    input_dir: String,
+    /// NMEA log, one `<host seconds> <sentence>` per line; RMC and ZDA sentences are used
+    #[arg(long)]
+    nmea: String,
+    /// PPS edge log, one `<host seconds> <sample counter>` per line, counting frames from the start of the first file
+    #[arg(long)]
+    pps: String,
+    /// Dir to write the clock csv to
+    #[arg(short, long)]
+    clock_dir: String,
+    /// How recording file names give their start time: nanos, iso8601 or datetime, tried in
+    /// order; all of them by default
+    #[arg(long, value_enum, value_delimiter = ',')]
+    name_patterns: Option<Vec<discover::NamePattern>>,
+    /// The receiver reports GPS time rather than UTC
+    #[arg(long)]
+    gps_time: bool,
+    /// GPS-UTC offset in seconds, used with --gps-time
+    #[arg(long, default_value_t = 18)]
+    leap_seconds: i64,
+}
+
+#[derive(clap::Args)]
+struct RepairArgs {
+    /// Wav files, or dirs whose recordings are checked
+    #[arg(required = true)]
+    paths: Vec<String>,
+    /// How recording file names give their start time: nanos, iso8601 or datetime, tried in
+    /// order; all of them by default
+    #[arg(long, value_enum, value_delimiter = ',')]
+    name_patterns: Option<Vec<discover::NamePattern>>,
+    /// Rewrite the headers of broken files in place and drop a partial last frame, instead of only reporting them
+    #[arg(long)]
+    write: bool,
 }
 
 #[derive(clap::Args)]
@@ -65,37 +197,120 @@ struct ConcatArgs {
     /// Path to a dir containing a single clock file
     #[arg(short, long)]
     clock_dir: String,
+    /// How recording file names give their start time: nanos, iso8601 or datetime, tried in
+    /// order; all of them by default
+    #[arg(long, value_enum, value_delimiter = ',')]
+    name_patterns: Option<Vec<discover::NamePattern>>,
     /// Step by that many samples
     #[arg(short, long)]
     step: Option<usize>,
+    /// Output audio format
+    #[arg(long, value_enum, default_value_t)]
+    format: Format,
+    /// Output name without extension, by default '{name}_{start}' with name being the stem
+    /// of --output
+    #[arg(long, value_parser = name::Template::parse)]
+    name_template: Option<name::Template>,
+    /// Write a .pps.csv sidecar with the UTC time of every PPS in the output
+    #[arg(long)]
+    timestamps: bool,
+    /// Print what the concat would read and write, without writing anything
+    #[arg(long)]
+    dry_run: bool,
 }
 
-#[derive(clap::Args)]
+#[derive(clap::Args, Clone)]
 struct Args {
+    /// Campaign config (wave.toml) giving the paths, mode and position of every module;
+    /// all configured modules are cut unless --module is given
+    #[arg(long)]
+    config: Option<String>,
     /// Path to output dir base
-    #[arg(short, long)]
-    output_dir: String,
+    #[arg(short, long, required_unless_present = "config")]
+    output_dir: Option<String>,
     /// Path to input directory containing wav files with names being numbers of nanoseconds since unix epoch
+    #[arg(short, long, required_unless_present = "config")]
+    input_dir: Option<String>,
+    /// Path to a csv clock dir which contains a single clock file (not needed with --clock-source pps)
     #[arg(short, long)]
-    input_dir: String,
-    /// Path to a csv clock dir which contains a single clock file
-    #[arg(short, long)]
-    clock_dir: String,
-    /// 'umc' or 'i2s'
-    #[arg(short, long)]
-    mode: String,
-    /// Start time as nanos from epoch
-    #[arg(long)]
+    clock_dir: Option<String>,
+    /// Where timing comes from: the clock csv (default), or PPS markers embedded in the audio
+    #[arg(long, value_enum)]
+    clock_source: Option<ClockSource>,
+    #[command(flatten)]
+    pps: pps::PpsLayout,
+    /// How recording file names give their start time: nanos, iso8601 or datetime, tried in
+    /// order; all of them unless set in --config
+    #[arg(long, value_enum, value_delimiter = ',')]
+    name_patterns: Option<Vec<discover::NamePattern>>,
+    /// Recorder profile: 'umc', 'i2s', 'rawi2s' or one defined in --config; a cuts file may
+    /// set it per row instead
+    #[arg(short, long, required_unless_present_any = ["cuts", "config"])]
+    mode: Option<String>,
+    /// Start time as RFC 3339 (e.g. 2024-06-12T10:03:00Z) or nanos from epoch
+    #[arg(long, value_parser = clock::parse_time)]
     start: Option<i64>,
+    /// End time as RFC 3339 or nanos from epoch
+    #[arg(long, value_parser = clock::parse_time, requires = "start", conflicts_with_all = ["samples", "duration"])]
+    end: Option<i64>,
+    /// Duration from start, e.g. 90s, 1.5m, 250ms
+    #[arg(long, value_parser = clock::parse_duration, requires = "start", conflicts_with = "samples")]
+    duration: Option<i64>,
+    /// Number of samples to write
     #[arg(long)]
     samples: Option<u64>,
+    /// Csv of cuts with columns start, end, range, flight and optionally modules, mode,
+    /// pre_roll, post_roll, label, output_name
     #[arg(long)]
     cuts: Option<String>,
+    /// Only cut the rows of the cuts file for this flight
+    #[arg(short, long)]
+    flight: Option<String>,
+    #[arg(long, required_unless_present = "config")]
+    module: Option<u8>,
+    /// Output audio format, wav unless set in --config
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+    /// Output name without extension, e.g. '{flight}/D{module}_{start:%H%M%S}'. Placeholders:
+    /// name, module, mode, flight, range, label, row, start[:strftime], and array, beam and
+    /// beam_angle for i2s beams
+    #[arg(long, value_parser = name::Template::parse)]
+    name_template: Option<name::Template>,
+    /// Write a .pps.csv sidecar with the UTC time of every PPS in each output
     #[arg(long)]
-    module: u8,
+    timestamps: bool,
+    /// Resample so sample k lies exactly at start + k / nominal rate in UTC, aligning modules
+    #[arg(long)]
+    resample: bool,
+    /// Warn when the estimated timing uncertainty of a cut exceeds this, e.g. 1ms, 50us
+    #[arg(long, value_parser = clock::parse_duration, default_value = "1ms")]
+    max_uncertainty: i64,
+    /// GPX or csv track of the drone, to write a .geometry.csv sidecar for each cut
+    #[arg(long, requires = "positions")]
+    track: Option<String>,
+    /// Csv of module positions with columns module, lat, lon, alt and optionally heading
+    #[arg(long, requires = "track")]
+    positions: Option<String>,
+    /// Rows per second in the geometry sidecar, at most 1000
+    #[arg(long, value_parser = track::parse_rate, default_value_t = 10.0)]
+    geometry_rate: f64,
+    /// Print what each cut would read and write, without writing anything
+    #[arg(long)]
+    dry_run: bool,
+    /// Recompute outputs which the manifest shows complete with the same parameters
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ClockSource {
+    #[default]
+    Csv,
+    Pps,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 struct Record {
     time: i64,
     sample: u64,
@@ -103,58 +318,236 @@ struct Record {
     file: String,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 struct CutRecord {
     start: String,
     end: String,
     range: String,
     flight: String,
+    /// Modules the cut applies to, e.g. `1-4;7`; all when missing
+    #[serde(default)]
+    modules: Option<String>,
+    #[serde(default)]
+    mode: Option<String>,
+    /// Extra time before `start`, e.g. 5s
+    #[serde(default)]
+    pre_roll: Option<String>,
+    /// Extra time after `end`
+    #[serde(default)]
+    post_roll: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+    /// Name template for the output instead of `D{module}_{row}`
+    #[serde(default)]
+    output_name: Option<String>,
 }
 
 struct Run {
+    /// Row of the cuts file, or 0
+    number: usize,
+    mode: String,
+    profile: profile::DeviceProfile,
     start: Option<i64>,
     samples: Option<u64>,
+    end: Option<i64>,
     output_dir_ext: String,
+    output_name: Option<name::Template>,
+    flight: Option<String>,
+    range: Option<String>,
+    label: Option<String>,
+}
+
+impl Run {
+    /// Metadata describing this run, stored as tags in formats which support them
+    fn comments(&self, module: u8) -> Vec<(String, String)> {
+        let mut comments = vec![("module".to_owned(), module.to_string())];
+        if let Some(flight) = &self.flight {
+            comments.push(("flight".to_owned(), flight.clone()));
+        }
+        if let Some(range) = &self.range {
+            comments.push(("range".to_owned(), range.clone()));
+        }
+        if let Some(label) = &self.label {
+            comments.push(("label".to_owned(), label.clone()));
+        }
+        if let Some(start) = self.start {
+            let start = DateTime::from_timestamp_nanos(start);
+            comments.push(("start_time".to_owned(), start.to_rfc3339()));
+        }
+        comments
+    }
+}
+
+impl Run {
+    /// Requested start and end, the end following from `samples` if not given
+    fn window(&self, samples: Option<u64>) -> Option<(i64, i64)> {
+        let start = self.start?;
+        let end = self.end.or_else(|| {
+            let frames = samples? as f64 / self.profile.samples_per_frame();
+            Some(start + (frames / self.profile.rate() * 1e9).round() as i64)
+        })?;
+        Some((start, end))
+    }
+
+    /// Samples to write, deriving them from the clock of `session` when the run is given by
+    /// its end time
+    fn samples(&self, session: Option<&clock::Session>) -> Option<u64> {
+        if self.samples.is_some() {
+            return self.samples;
+        }
+        let (start, end) = (self.start?, self.end?);
+        let rate = self.profile.rate();
+        let frames = session
+            .and_then(|s| clock::frames_between(&s.records, &s.rates, start, end, rate))
+            .unwrap_or((end - start) as f64 / 1e9 * rate);
+        Some((frames * self.profile.samples_per_frame()).round().max(0.0) as u64)
+    }
+}
+
+/// Parses a module list such as `1-4;7` (`,` and spaces also separate)
+fn parse_modules(s: &str) -> Result<Vec<u8>, String> {
+    let mut modules = Vec::new();
+    for part in s.split([';', ',', ' ']).filter(|p| !p.is_empty()) {
+        let parse = |m: &str| {
+            m.trim()
+                .parse::<u8>()
+                .map_err(|_| format!("unknown module '{m}'"))
+        };
+        match part.split_once('-') {
+            Some((a, b)) => {
+                let (a, b) = (parse(a)?, parse(b)?);
+                if a > b {
+                    return Err(format!("empty module range '{part}'"));
+                }
+                modules.extend(a..=b);
+            }
+            None => modules.push(parse(part)?),
+        }
+    }
+    Ok(modules)
 }
 
+fn parse_rfc3339(s: &str) -> Result<i64, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map_err(|e| format!("invalid time '{s}': {e}"))?
+        .timestamp_nanos_opt()
+        .ok_or_else(|| format!("time '{s}' out of range"))
+}
+
+/// Name of a cut's output without a name template
+const DEFAULT_NAME: &str = "D{module}_{row}";
+
+/// One run for a single cut given on the command line, or the rows of the cuts file that
+/// apply to `module`. Invalid cuts files are reported and yield no runs.
+#[allow(clippy::too_many_arguments)]
 fn runs(
     start: Option<i64>,
     samples: Option<u64>,
+    end: Option<i64>,
     cuts: Option<String>,
-    mode: &str,
+    flight: Option<&str>,
+    mode: Option<&str>,
+    profiles: &profile::Profiles,
     module: u8,
 ) -> Vec<Run> {
     let Some(cuts) = cuts else {
+        let mode = mode.unwrap_or_default();
+        let profile = match profiles.get(mode) {
+            Ok(profile) => profile.clone(),
+            Err(e) => {
+                log::error(Class::Input, e);
+                return Vec::new();
+            }
+        };
         return vec![Run {
+            number: 0,
+            mode: mode.to_owned(),
+            profile,
             start,
             samples,
+            end,
             output_dir_ext: format!("{mode}/{module}"),
+            output_name: None,
+            flight: None,
+            range: None,
+            label: None,
         }];
     };
 
-    let (channels, sample_rate) = if mode == "rawi2s" {
-        (4, 192000)
-    } else {
-        (1, 48000)
+    let mut reader = match csv::Reader::from_path(&cuts) {
+        Ok(reader) => reader,
+        Err(e) => {
+            log::error(Class::Input, format!("Reading cuts file {cuts}: {e}"));
+            return Vec::new();
+        }
     };
-
-    let mut reader = csv::Reader::from_path(cuts).unwrap();
-    let records = reader.deserialize();
     let mut runs = Vec::new();
+    let mut errors = Vec::new();
+    let mut listed = false;
+    let mut flown = false;
+
+    for (i, r) in reader.deserialize().enumerate() {
+        // Header is line 1
+        let line = i + 2;
+        let cut: CutRecord = match r {
+            Ok(cut) => cut,
+            Err(e) => {
+                errors.push(format!("line {line}: {e}"));
+                continue;
+            }
+        };
+        let parsed = (|| {
+            let start_nanos = parse_rfc3339(&cut.start)?;
+            let end_nanos = parse_rfc3339(&cut.end)?;
+            if end_nanos <= start_nanos {
+                return Err("end is not after start".to_owned());
+            }
+            let pre_roll = cut
+                .pre_roll
+                .as_deref()
+                .map_or(Ok(0), clock::parse_duration)?;
+            let post_roll = cut
+                .post_roll
+                .as_deref()
+                .map_or(Ok(0), clock::parse_duration)?;
+            let modules = cut.modules.as_deref().map(parse_modules).transpose()?;
+            let Some(mode) = cut.mode.as_deref().or(mode) else {
+                return Err("no mode in the row or on the command line".to_owned());
+            };
+            let profile = profiles.get(mode)?.clone();
+            let output_name = cut
+                .output_name
+                .as_deref()
+                .filter(|n| !n.is_empty())
+                .map(name::Template::parse)
+                .transpose()?;
+            Ok((
+                start_nanos - pre_roll,
+                end_nanos + post_roll,
+                modules,
+                mode.to_owned(),
+                profile,
+                output_name,
+            ))
+        })();
+        let (start_nanos, end_nanos, modules, mode, profile, output_name) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                errors.push(format!("line {line}: {e}"));
+                continue;
+            }
+        };
+        if flight.is_some_and(|f| f != cut.flight) {
+            continue;
+        }
+        flown = true;
+        if let Some(modules) = modules {
+            if !modules.contains(&module) {
+                continue;
+            }
+            listed = true;
+        }
 
-    for r in records {
-        let cut: CutRecord = r.unwrap();
-        let start_nanos = DateTime::parse_from_rfc3339(&cut.start)
-            .unwrap()
-            .timestamp_nanos_opt()
-            .unwrap();
-        let end_nanos = DateTime::parse_from_rfc3339(&cut.end)
-            .unwrap()
-            .timestamp_nanos_opt()
-            .unwrap();
-        let samples =
-            ((end_nanos - start_nanos) as f64 / 1e9f64 * channels as f64 * sample_rate as f64)
-                .round() as u64;
         let flight_name = if cut.flight != "." {
             format!("flight_{}/", cut.flight)
         } else {
@@ -166,93 +559,841 @@ fn runs(
             "".to_owned()
         };
         runs.push(Run {
-            start: Some(start_nanos),
-            samples: Some(samples),
+            number: i,
             output_dir_ext: format!("{mode}/{flight_name}{module}/{range_name}"),
+            mode,
+            profile,
+            start: Some(start_nanos),
+            samples: None,
+            end: Some(end_nanos),
+            output_name,
+            flight: (cut.flight != ".").then_some(cut.flight),
+            range: (cut.range != ".").then_some(cut.range),
+            label: cut.label.filter(|l| !l.is_empty()),
         });
     }
 
+    if !errors.is_empty() {
+        for e in &errors {
+            log::error(Class::Input, format!("{cuts}: {e}"));
+        }
+        return Vec::new();
+    }
+    if let Some(flight) = flight.filter(|_| !flown) {
+        log::error(Class::Input, format!("{cuts}: no cuts for flight {flight}"));
+    } else if !listed && runs.is_empty() {
+        log::warn(format!("{cuts}: module {module} is not listed in any cut"));
+    }
+
+    let mut sorted = runs.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|r| r.start);
+    for w in sorted.windows(2) {
+        if w[1].start < w[0].end {
+            log::warn(format!(
+                "{cuts}: cuts on lines {} and {} overlap",
+                w[0].number + 2,
+                w[1].number + 2
+            ));
+        }
+    }
+
     runs
 }
 
-fn main() {
-    let cli = Cli::parse();
-
-    match cli.command {
-        Commands::Cut(args) => {
-            for (i, run) in runs(args.start, args.samples, args.cuts, &args.mode, args.module)
-                .iter()
-                .enumerate()
+/// Cuts the recordings of one module
+fn cut(args: Args, profiles: &profile::Profiles, ground_truth: Option<track::GroundTruth>) {
+    let format = args.format.unwrap_or_default();
+    let clock_source = args.clock_source.unwrap_or_default();
+    let patterns = args.name_patterns.as_deref().unwrap_or(&discover::PATTERNS);
+    if clock_source == ClockSource::Csv && args.clock_dir.is_none() {
+        log::error(
+            Class::Input,
+            "--clock-dir is required unless --clock-source is 'pps'",
+        );
+        return;
+    }
+    let (Some(output_dir), Some(input_dir), Some(module)) =
+        (&args.output_dir, &args.input_dir, args.module)
+    else {
+        unreachable!("clap requires them without --config")
+    };
+    let clock_dir = args
+        .clock_dir
+        .as_ref()
+        .filter(|_| clock_source == ClockSource::Csv);
+    if !check_dir("Input", input_dir) || clock_dir.is_some_and(|d| !check_dir("Clock", d)) {
+        return;
+    }
+    if args.flight.is_some() && args.cuts.is_none() {
+        log::error(
+            Class::Input,
+            "--flight selects rows of a cuts file, which was not given",
+        );
+        return;
+    }
+    let mut index = index::Index::open(input_dir);
+    let end = args
+        .end
+        .or(args.start.zip(args.duration).map(|(s, d)| s + d));
+    let mut plans = args.dry_run.then(Vec::new);
+    let ext = format.extension();
+    for run in runs(
+        args.start,
+        args.samples,
+        end,
+        args.cuts.clone(),
+        args.flight.as_deref(),
+        args.mode.as_deref(),
+        profiles,
+        module,
+    )
+    .iter()
+    {
+        let i = run.number;
+        let mode = &run.mode;
+        let profile = &run.profile;
+        let sessions = match clock_dir {
+            Some(clock_dir) => {
+                clock::sessions(clock_dir, input_dir, patterns, profile.rate(), &mut index)
+            }
+            None => Vec::new(),
+        };
+        let template = run.output_name.as_ref().or(args.name_template.as_ref());
+        let fields = name::Fields {
+            module: Some(module),
+            mode: Some(mode.clone()),
+            flight: run.flight.clone(),
+            range: run.range.clone(),
+            label: run.label.clone(),
+            row: Some(i),
+            start: run.start,
+            ..Default::default()
+        };
+        let output_dir = format!("{output_dir}/{}", run.output_dir_ext);
+        if !args.dry_run {
+            if let Err(err) = std::fs::create_dir_all(&output_dir) {
+                log::error(Class::Io, format!("Creating dir: {err}"));
+            }
+        }
+        let options = Options {
+            format,
+            comments: run.comments(module),
+            timestamps: args.timestamps,
+            resample: args.resample,
+            max_uncertainty: Some(args.max_uncertainty),
+            name_template: template.cloned(),
+            fields: fields.clone(),
+            parameters: None,
+        };
+        let parameters = manifest::Parameters {
+            input_dir: input_dir.clone(),
+            name_patterns: patterns.to_vec(),
+            clock_dir: None,
+            mode: mode.clone(),
+            profile: profile.clone(),
+            pps: None,
+            start: run.start,
+            samples: None,
+            format,
+            resample: args.resample,
+            timestamps: args.timestamps,
+            name_template: template.map(|t| t.as_str().to_owned()),
+            comments: options.comments.clone(),
+        };
+        if clock_source == ClockSource::Pps {
+            let name = name::file_name(template, DEFAULT_NAME, &fields);
+            let output = format!("{output_dir}/{name}.{ext}");
+            let (Some(start), Some(samples)) = (run.start, run.samples(None)) else {
+                plan::fail(
+                    &mut plans,
+                    &output,
+                    Class::Input,
+                    "PPS clock source needs a start and an end, duration or sample count",
+                );
+                continue;
+            };
+            // --pps-* flags given on the command line take precedence over the profile
+            let layout = match &profile.pps {
+                Some(layout) if args.pps == pps::PpsLayout::default() => layout,
+                Some(_) => &args.pps,
+                None => {
+                    let message = format!("Mode '{mode}' does not embed PPS markers in the audio");
+                    plan::fail(&mut plans, &output, Class::Input, &message);
+                    continue;
+                }
+            };
+            if profile.decoder != profile::Decoder::Channels {
+                let message = format!("PPS clock source is not supported in {mode} mode");
+                plan::fail(&mut plans, &output, Class::Input, &message);
+                continue;
+            }
+            let parameters = manifest::Parameters {
+                pps: Some(layout.clone()),
+                samples: Some(samples),
+                ..parameters
+            };
+            let done = !args.force && manifest::done(&[output.clone().into()], &parameters);
+            let options = Options {
+                parameters: Some(parameters),
+                ..options
+            };
+            if let Some(plans) = &mut plans {
+                if done {
+                    plans.push(plan::skip(&output));
+                    continue;
+                }
+                plans.push(plan::pps(
+                    &output,
+                    input_dir.as_ref(),
+                    patterns,
+                    start,
+                    samples,
+                    profile,
+                    layout,
+                    &mut index,
+                ));
+                continue;
+            }
+            let errors = log::errors();
+            if done {
+                log::info(format!(
+                    "Skipping {output}, already cut with the same parameters"
+                ));
+            } else {
+                umc::make_wav_pps(
+                    &output, input_dir, patterns, start, samples, profile, layout, &mut index,
+                    &options,
+                );
+            }
+            let wanted = wants_geometry(outcome(done, errors), &output);
+            if let Some((gt, (start, end))) = ground_truth
+                .as_ref()
+                .zip(run.window(Some(samples)))
+                .filter(|_| wanted)
             {
-                let output_dir = format!("{}/{}", &args.output_dir, run.output_dir_ext);
-                match std::fs::create_dir_all(&output_dir) {
-                    Ok(_) => {}
-                    Err(err) => eprintln!("Creating dir: {err}"),
+                gt.write(&output, start, end);
+            }
+            log::outcome(outcome(done, errors));
+            continue;
+        }
+        // let clock_file = std::fs::read_dir(&args.clock_dir)
+        //     .unwrap()
+        //     .next()
+        //     .unwrap()
+        //     .unwrap()
+        //     .path()
+        //     .to_str()
+        //     .unwrap()
+        //     .to_owned();
+        // Without a start every session is cut whole, otherwise the sessions
+        // overlapping the run are stitched into one output
+        let groups = match run.start {
+            None => sessions
+                .iter()
+                .map(|s| {
+                    let suffix = if sessions.len() > 1 {
+                        format!("_{}", s.name())
+                    } else {
+                        String::new()
+                    };
+                    (suffix, vec![s])
+                })
+                .collect::<Vec<_>>(),
+            Some(start) => {
+                let (_, end) = run.window(run.samples).unwrap_or((start, start));
+                let covering = sessions
+                    .iter()
+                    .filter(|s| s.overlaps(start, end))
+                    .collect::<Vec<_>>();
+                if covering.is_empty() {
+                    let name = name::file_name(template, DEFAULT_NAME, &fields);
+                    plan::fail(
+                        &mut plans,
+                        &format!("{output_dir}/{name}.{ext}"),
+                        Class::OutOfRange,
+                        "Requested start not in audio data time range",
+                    );
+                    continue;
                 }
-                let output = format!("{}/D{}_{i}.wav", output_dir, args.module);
-                let mode = &args.mode;
-                // let clock_file = std::fs::read_dir(&args.clock_dir)
-                //     .unwrap()
-                //     .next()
-                //     .unwrap()
-                //     .unwrap()
-                //     .path()
-                //     .to_str()
-                //     .unwrap()
-                //     .to_owned();
-                let clock_files = std::fs::read_dir(&args.clock_dir).unwrap();
-                for clock_file in clock_files {
-                    let clock_file = clock_file.unwrap().path().to_str().unwrap().to_owned();
-                    if mode == "umc" {
-                        umc::make_wav(
-                            &output,
-                            &args.input_dir,
-                            &clock_file,
-                            run.start,
-                            run.samples,
-                            None,
-                            None,
-                            None,
-                        );
-                    } else if mode == "i2s" {
-                        i2s::make_wav(
-                            &output,
-                            &args.input_dir,
-                            &clock_file,
-                            run.start,
-                            run.samples,
-                        );
-                    } else if mode == "rawi2s" {
-                        umc::make_wav(
-                            &output,
-                            &args.input_dir,
-                            &clock_file,
-                            run.start,
-                            run.samples,
-                            Some(1),
-                            Some(4),
-                            Some(192000),
+                vec![(String::new(), covering)]
+            }
+        };
+        for (suffix, covering) in groups {
+            let fields = name::Fields {
+                start: Some(run.start.unwrap_or(covering[0].start)),
+                ..fields.clone()
+            };
+            let suffix = if template.is_some_and(|t| t.uses("start")) {
+                String::new()
+            } else {
+                suffix
+            };
+            let name = name::file_name(template, DEFAULT_NAME, &fields) + &suffix;
+            let output = format!("{output_dir}/{name}.{ext}");
+            // Resampled outputs run at exactly the nominal rate
+            let samples = run.samples(Some(covering[0]).filter(|_| !args.resample));
+            let parameters = manifest::Parameters {
+                clock_dir: args.clock_dir.clone(),
+                start: run.start.or(Some(covering[0].start)),
+                samples,
+                ..parameters.clone()
+            };
+            let options = Options {
+                fields: name::Fields {
+                    name: Some(name.clone()),
+                    ..fields
+                },
+                parameters: Some(parameters.clone()),
+                ..options.clone()
+            };
+            // Beams are named after the output, without its extension
+            let base = format!("{output_dir}/{name}");
+            let (shown, outputs) = match profile.decoder {
+                profile::Decoder::Channels => (output.clone(), vec![output.clone().into()]),
+                profile::Decoder::I2sBeams => (
+                    format!("{base}_*.{ext}"),
+                    i2s::outputs(base.as_ref(), &options),
+                ),
+            };
+            let done = !args.force && manifest::done(&outputs, &parameters);
+            let mut warnings = Vec::new();
+            if profile.decoder == profile::Decoder::I2sBeams {
+                if args.resample {
+                    warnings.push(format!(
+                        "--resample is not supported in {mode} mode, writing as recorded"
+                    ));
+                }
+                if covering.len() > 1 {
+                    warnings.push(format!(
+                        "{mode} cuts are not stitched across sessions, output ends at the end of session {}",
+                        covering[0].name()
+                    ));
+                }
+            }
+            if let Some(plans) = &mut plans {
+                if done {
+                    plans.push(plan::skip(&shown));
+                    continue;
+                }
+                let covering = match profile.decoder {
+                    profile::Decoder::Channels => &covering[..],
+                    profile::Decoder::I2sBeams => &covering[..1],
+                };
+                let mut plan = plan::clock(
+                    &shown,
+                    input_dir.as_ref(),
+                    patterns,
+                    covering,
+                    run.start,
+                    samples,
+                    profile,
+                    &mut index,
+                );
+                warnings.iter().for_each(|w| plan.warn(w));
+                plans.push(plan);
+                continue;
+            }
+            let errors = log::errors();
+            if done {
+                log::info(format!(
+                    "Skipping {shown}, already cut with the same parameters"
+                ));
+            } else {
+                warnings.iter().for_each(log::warn);
+            }
+            match profile.decoder {
+                _ if done => {}
+                profile::Decoder::Channels => umc::make_wav(
+                    &output, input_dir, patterns, &covering, run.start, samples, profile, &options,
+                ),
+                profile::Decoder::I2sBeams => {
+                    i2s::make_wav(
+                        &base,
+                        input_dir,
+                        patterns,
+                        &covering[0].clock.to_str().unwrap().to_owned(),
+                        run.start,
+                        samples,
+                        profile,
+                        &options,
+                    );
+                }
+            }
+            let wanted = wants_geometry(outcome(done, errors), &output);
+            if let Some(gt) = ground_truth.as_ref().filter(|_| wanted) {
+                let session = (covering[0].start, covering[covering.len() - 1].end);
+                let (start, end) = run.window(samples).unwrap_or(session);
+                gt.write(&output, start, end);
+            }
+            log::outcome(outcome(done, errors));
+        }
+    }
+    index.save();
+    if let Some(plans) = plans {
+        plan::print(&plans);
+    }
+}
+
+/// Whether `dir` is a directory, logging an input error naming it `what` if not
+fn check_dir(what: &str, dir: &str) -> bool {
+    let is_dir = std::path::Path::new(dir).is_dir();
+    if !is_dir {
+        log::error(Class::Input, format!("{what} dir {dir} not found"));
+    }
+    is_dir
+}
+
+/// How the job of an output ended, given whether it was skipped and the errors logged before
+/// it started
+fn outcome(skipped: bool, errors: usize) -> Outcome {
+    if skipped {
+        Outcome::Skipped
+    } else if log::errors() > errors {
+        Outcome::Failed
+    } else {
+        Outcome::Written
+    }
+}
+
+/// Geometry goes with outputs written now, and with skipped ones which lack it, say after
+/// `--track` was added on a rerun. Failing to write it fails the job.
+fn wants_geometry(outcome: Outcome, output: &str) -> bool {
+    match outcome {
+        Outcome::Written => true,
+        Outcome::Skipped => track::GroundTruth::missing(output),
+        Outcome::Failed => false,
+    }
+}
+
+/// Ground truth for `module`, or None with a warning when it has no position
+fn ground_truth(
+    track: &std::path::Path,
+    positions: Vec<track::ModulePosition>,
+    module: u8,
+    rate: f64,
+) -> Result<Option<track::GroundTruth>, String> {
+    let track = track::read_track(track)?;
+    let Some(position) = positions.into_iter().find(|p| p.module == module) else {
+        log::warn(format!("Module {module} has no position"));
+        return Ok(None);
+    };
+    Ok(Some(track::GroundTruth {
+        track,
+        position,
+        rate,
+    }))
+}
+
+/// Cuts every module of a campaign config; paths, mode, cuts and output settings given on the
+/// command line take precedence over the file
+fn cut_campaign(path: &str, args: Args) {
+    let config = match config::Config::load(path.as_ref()) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error(Class::Input, format!("Reading config {path}: {e}"));
+            return;
+        }
+    };
+    let profiles = profile::Profiles::with(&config.profiles);
+    let display = |p: &std::path::Path| p.display().to_string();
+    let Some(output_dir) = args
+        .output_dir
+        .clone()
+        .or(config.output_dir.as_deref().map(display))
+    else {
+        log::error(
+            Class::Input,
+            format!("No output dir on the command line or in {path}"),
+        );
+        return;
+    };
+    if let Some(module) = args.module {
+        if !config.modules.iter().any(|m| m.module == module) {
+            log::error(
+                Class::Input,
+                format!("Module {module} is not configured in {path}"),
+            );
+            return;
+        }
+    }
+    let cuts = args.cuts.clone().or(config.cuts.as_deref().map(display));
+    if let Some(cuts) = &cuts {
+        check_cut_modules(cuts, &config);
+    }
+    let track = args.track.as_ref().map(std::path::PathBuf::from).or(config
+        .track(args.flight.as_deref())
+        .map(|p| p.to_path_buf()));
+
+    for m in &config.modules {
+        if args.module.is_some_and(|module| module != m.module) {
+            continue;
+        }
+        let ground_truth = match &track {
+            Some(track) => {
+                match ground_truth(track, config.positions(), m.module, args.geometry_rate) {
+                    Ok(ground_truth) => ground_truth,
+                    Err(e) => {
+                        log::error(
+                            Class::Input,
+                            format!("Reading track {}: {e}", track.display()),
                         );
-                    } else {
-                        eprintln!("Mode can be 'umc', 'i2s' or 'rawi2s'");
+                        return;
                     }
                 }
             }
+            None => None,
+        };
+        let job = Args {
+            config: None,
+            output_dir: Some(output_dir.clone()),
+            input_dir: Some(display(&m.input_dir)),
+            clock_dir: args
+                .clock_dir
+                .clone()
+                .or(m.clock_dir.as_deref().map(display)),
+            clock_source: args.clock_source.or(config.clock_source),
+            mode: args.mode.clone().or(m.mode.clone()).or(config.mode.clone()),
+            cuts: cuts.clone(),
+            module: Some(m.module),
+            format: args.format.or(config.format),
+            timestamps: args.timestamps || config.timestamps,
+            resample: args.resample || config.resample,
+            name_template: args.name_template.clone().or(config.name_template.clone()),
+            name_patterns: args.name_patterns.clone().or(config.name_patterns.clone()),
+            ..args.clone()
+        };
+        if job.mode.is_none() && job.cuts.is_none() {
+            log::error(
+                Class::Input,
+                format!("Module {}: no mode configured and no cuts file", m.module),
+            );
+            continue;
         }
+        cut(job, &profiles, ground_truth);
+    }
+}
+
+/// Warns about modules a cuts file lists which the campaign has no recordings for
+fn check_cut_modules(cuts: &str, config: &config::Config) {
+    let Ok(mut reader) = csv::Reader::from_path(cuts) else {
+        // Reported when the cuts are run
+        return;
+    };
+    for (i, cut) in reader.deserialize::<CutRecord>().enumerate() {
+        let Some(modules) = cut.ok().and_then(|c| c.modules) else {
+            continue;
+        };
+        for module in parse_modules(&modules).unwrap_or_default() {
+            if !config.modules.iter().any(|m| m.module == module) {
+                log::warn(format!(
+                    "{cuts}: line {} lists module {module}, which is not configured",
+                    i + 2
+                ));
+            }
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let log_file = cli.log_file.as_ref().map(std::path::Path::new);
+    if let Err(e) = log::init(cli.log_level, cli.quiet, log_file) {
+        eprintln!("{e}");
+        std::process::exit(Class::Input.code());
+    }
+    run(cli.command);
+    std::process::exit(log::finish());
+}
+
+fn run(command: Commands) {
+    match command {
+        Commands::Cut(args) => match args.config.clone() {
+            Some(path) => cut_campaign(&path, args),
+            None => {
+                let ground_truth = match (&args.track, &args.positions) {
+                    (Some(track), Some(positions)) => {
+                        match track::read_modules(positions.as_ref()).and_then(|positions| {
+                            ground_truth(
+                                track.as_ref(),
+                                positions,
+                                args.module.unwrap(),
+                                args.geometry_rate,
+                            )
+                        }) {
+                            Ok(ground_truth) => ground_truth,
+                            Err(e) => {
+                                log::error(
+                                    Class::Input,
+                                    format!("Reading track or positions: {e}"),
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                cut(args, &profile::Profiles::default(), ground_truth);
+            }
+        },
         Commands::Concat(args) => {
-            let clock_file = std::fs::read_dir(&args.clock_dir)
-                .unwrap()
-                .next()
-                .unwrap()
-                .unwrap()
-                .path()
-                .to_str()
-                .unwrap()
-                .to_owned();
-            concat(args.input_dir, args.output, clock_file, args.step.unwrap_or(1));
+            if !check_dir("Input", &args.input_dir) {
+                return;
+            }
+            let entries = match std::fs::read_dir(&args.clock_dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::error(
+                        Class::Input,
+                        format!("Reading clock dir {}: {e}", args.clock_dir),
+                    );
+                    return;
+                }
+            };
+            let Some(clock_file) = entries
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|e| e == "csv"))
+                .min()
+            else {
+                log::error(Class::Input, "No clock csv found in clock dir");
+                return;
+            };
+            let clock_file = clock_file.to_str().unwrap().to_owned();
+            let options = Options {
+                format: args.format,
+                timestamps: args.timestamps,
+                name_template: args.name_template,
+                ..Default::default()
+            };
+            let patterns = args.name_patterns.as_deref().unwrap_or(&discover::PATTERNS);
+            if args.dry_run {
+                let mut index = index::Index::open(&args.input_dir);
+                let plan = concat::plan(
+                    &args.input_dir,
+                    patterns,
+                    &args.output,
+                    &clock_file,
+                    args.step.unwrap_or(1),
+                    &options,
+                    &mut index,
+                );
+                index.save();
+                plan::print(&[plan]);
+                return;
+            }
+            concat(
+                args.input_dir,
+                patterns,
+                args.output,
+                clock_file,
+                args.step.unwrap_or(1),
+                &options,
+            );
         }
         Commands::CutOne(args) => {
-            cut_one::make_wav(args.output, args.input, args.start, args.samples);
+            let start = match (args.start, args.start_time) {
+                (Some(sample), _) => cut_one::Position::Sample(sample),
+                (None, Some(nanos)) => cut_one::Position::Time(nanos),
+                (None, None) => unreachable!("clap requires a start"),
+            };
+            let length = match (args.samples, args.duration, args.end_time) {
+                (Some(samples), _, _) => cut_one::Length::Samples(samples),
+                (None, Some(nanos), _) => cut_one::Length::Duration(nanos),
+                (None, None, Some(nanos)) => cut_one::Length::Until(nanos),
+                (None, None, None) => unreachable!("clap requires a length"),
+            };
+            cut_one::make_wav(
+                args.output,
+                args.input,
+                start,
+                length,
+                &args.pps,
+                &Options {
+                    format: args.format,
+                    timestamps: args.timestamps,
+                    name_template: args.name_template,
+                    ..Default::default()
+                },
+            );
+        }
+        Commands::ImportClock(args) => {
+            if !check_dir("Input", &args.input_dir) {
+                return;
+            }
+            nmea::import(
+                args.input_dir,
+                args.name_patterns.as_deref().unwrap_or(&discover::PATTERNS),
+                args.nmea,
+                args.pps,
+                args.clock_dir,
+                if args.gps_time { args.leap_seconds } else { 0 },
+            );
+        }
+        Commands::Repair(args) => repair::repair(
+            &args.paths,
+            args.name_patterns.as_deref().unwrap_or(&discover::PATTERNS),
+            args.write,
+        ),
+        Commands::Cuts {
+            command: CutsCommands::FromTrack(args),
+        } => {
+            let config = match args
+                .config
+                .as_deref()
+                .map(|p| config::Config::load(p.as_ref()))
+            {
+                Some(Ok(config)) => Some(config),
+                Some(Err(e)) => {
+                    log::error(
+                        Class::Input,
+                        format!("Reading config {}: {e}", args.config.unwrap()),
+                    );
+                    return;
+                }
+                None => None,
+            };
+            let Some(track_path) = args.track.as_ref().map(std::path::PathBuf::from).or(config
+                .as_ref()
+                .and_then(|c| c.track(Some(&args.flight)).map(|p| p.to_path_buf())))
+            else {
+                log::error(
+                    Class::Input,
+                    format!("No track for flight {} in the config", args.flight),
+                );
+                return;
+            };
+            let modules = match (&args.modules, &config) {
+                (Some(path), _) => track::read_modules(path.as_ref()),
+                (None, Some(config)) => Ok(config.positions()),
+                (None, None) => unreachable!("clap requires modules without --config"),
+            };
+            let (track, modules) = match (track::read_track(&track_path), modules) {
+                (Ok(track), Ok(modules)) => (track, modules),
+                (Err(e), _) => {
+                    log::error(
+                        Class::Input,
+                        format!("Reading track {}: {e}", track_path.display()),
+                    );
+                    return;
+                }
+                (_, Err(e)) => {
+                    log::error(Class::Input, format!("Reading modules: {e}"));
+                    return;
+                }
+            };
+            if args.ranges.windows(2).any(|w| w[0] >= w[1]) {
+                log::error(Class::Input, "--ranges must be increasing");
+                return;
+            }
+            let cuts = track::cuts(
+                &track,
+                &modules,
+                &args.ranges,
+                &args.flight,
+                args.min_duration,
+            );
+            let out: Box<dyn std::io::Write> = match &args.output {
+                Some(path) => match std::fs::File::create(path) {
+                    Ok(file) => Box::new(file),
+                    Err(e) => {
+                        log::error(Class::Io, format!("Creating {path}: {e}"));
+                        return;
+                    }
+                },
+                None => Box::new(std::io::stdout()),
+            };
+            let mut writer = csv::Writer::from_writer(out);
+            let written = cuts
+                .iter()
+                .try_for_each(|cut| writer.serialize(cut))
+                .and_then(|()| Ok(writer.flush()?));
+            if let Err(e) = written {
+                let path = args.output.as_deref().unwrap_or("stdout");
+                log::error(Class::Io, format!("Writing cuts to {path}: {e}"));
+            }
         } // Commands::ConcatCutsFlights(_args) => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_geometry_missing_from_skipped_outputs() {
+        let dir = testing::dir();
+        let output = dir.path().join("D1_0.wav");
+        let output = output.to_str().unwrap();
+        assert!(wants_geometry(Outcome::Written, output));
+        assert!(wants_geometry(Outcome::Skipped, output));
+        assert!(!wants_geometry(Outcome::Failed, output));
+        std::fs::write(dir.path().join("D1_0.geometry.csv"), b"").unwrap();
+        assert!(wants_geometry(Outcome::Written, output));
+        assert!(!wants_geometry(Outcome::Skipped, output));
+    }
+
+    #[test]
+    fn parses_module_lists() {
+        assert_eq!(parse_modules("1-4;7"), Ok(vec![1, 2, 3, 4, 7]));
+        assert_eq!(parse_modules("2, 5 9"), Ok(vec![2, 5, 9]));
+        assert!(parse_modules("4-1").is_err());
+        assert!(parse_modules("1;x").is_err());
+    }
+
+    #[test]
+    fn reads_per_row_settings_from_cuts_files() {
+        let _serial = testing::serial();
+        let dir = testing::dir();
+        let cuts = dir.path().join("cuts.csv");
+        std::fs::write(
+            &cuts,
+            "start,end,range,flight,modules,mode,pre_roll,post_roll,label,output_name\n\
+             2024-06-12T10:03:00Z,2024-06-12T10:03:10Z,0-100,3,1-4,i2s,2s,1s,hover,{label}_{module}\n\
+             2024-06-12T10:04:00Z,2024-06-12T10:04:10Z,.,3,5,,,,,\n\
+             2024-06-12T10:05:00Z,2024-06-12T10:05:10Z,.,4,,,,,,\n",
+        )
+        .unwrap();
+        let profiles = profile::Profiles::default();
+        let runs = |flight, module| {
+            let cuts = Some(cuts.to_str().unwrap().to_owned());
+            runs(
+                None,
+                None,
+                None,
+                cuts,
+                flight,
+                Some("umc"),
+                &profiles,
+                module,
+            )
+        };
+
+        let runs_3 = runs(Some("3"), 3);
+        assert_eq!(runs_3.len(), 1);
+        let run = &runs_3[0];
+        assert_eq!((run.number, run.mode.as_str()), (0, "i2s"));
+        let start = parse_rfc3339("2024-06-12T10:02:58Z").unwrap();
+        let end = parse_rfc3339("2024-06-12T10:03:11Z").unwrap();
+        assert_eq!((run.start, run.end), (Some(start), Some(end)));
+        assert_eq!(run.output_dir_ext, "i2s/flight_3/3/0-100/");
+        assert_eq!(run.label.as_deref(), Some("hover"));
+        assert!(run.output_name.as_ref().is_some_and(|t| t.uses("label")));
+
+        // Rows without modules apply to every module, in the mode given on the command line
+        let runs_5 = runs(None, 5);
+        let numbers = runs_5.iter().map(|r| r.number).collect::<Vec<_>>();
+        assert_eq!(numbers, [1, 2]);
+        assert_eq!(runs_5[1].mode, "umc");
+        assert_eq!(runs_5[1].output_dir_ext, "umc/flight_4/5/");
+
+        // One invalid row rejects the whole file
+        let errors = log::errors();
+        std::fs::write(
+            &cuts,
+            "start,end,range,flight\n2024-06-12T10:03:10Z,2024-06-12T10:03:00Z,.,3\n",
+        )
+        .unwrap();
+        assert!(runs(None, 1).is_empty());
+        assert_eq!(log::errors(), errors + 1);
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::discover::{self, NamePattern};
+use crate::log::{self, Class};
+use crate::output::Format;
+use crate::pps::PpsLayout;
+use crate::profile::DeviceProfile;
+use crate::timing::Timing;
+
+const FILE_NAME: &str = "manifest.json";
+
+/// Frames `start..end` of an input file that went into an output
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Source {
+    pub file: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Silence written in place of missing recordings
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Gap {
+    /// Output frame the silence starts at
+    pub sample: u64,
+    pub frames: u64,
+}
+
+/// Where an output's samples came from
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Provenance {
+    pub sources: Vec<Source>,
+    pub clocks: Vec<String>,
+    pub requested_start: Option<i64>,
+    /// UTC nanos of the first sample as cut, after rounding to whole input frames
+    pub achieved_start: Option<i64>,
+    pub gaps: Vec<Gap>,
+}
+
+impl Provenance {
+    /// Records that frames `start..end` of `file` were read
+    pub fn read(&mut self, file: &Path, start: u64, end: u64) {
+        self.sources.push(Source {
+            file: file.display().to_string(),
+            start,
+            end,
+        });
+    }
+}
+
+/// What a cut was asked for; a rerun asking for the same may skip the output
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Parameters {
+    pub input_dir: String,
+    /// Patterns the recordings were found by, the defaults in manifests written before
+    /// they could be chosen
+    #[serde(default = "default_patterns")]
+    pub name_patterns: Vec<NamePattern>,
+    /// Clock csv dir, or none when timed by the PPS markers in the audio
+    pub clock_dir: Option<String>,
+    pub mode: String,
+    pub profile: DeviceProfile,
+    /// PPS layout used to time the cut, if any
+    pub pps: Option<PpsLayout>,
+    pub start: Option<i64>,
+    pub samples: Option<u64>,
+    pub format: Format,
+    pub resample: bool,
+    pub timestamps: bool,
+    /// Template the outputs were named by, if any
+    #[serde(default)]
+    pub name_template: Option<String>,
+    /// Tags written into the outputs, such as the flight and label
+    #[serde(default)]
+    pub comments: Vec<(String, String)>,
+}
+
+fn default_patterns() -> Vec<NamePattern> {
+    discover::PATTERNS.to_vec()
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    provenance: Provenance,
+    /// Absent for outputs of concat and cut-one, which are always rewritten
+    #[serde(default)]
+    parameters: Option<Parameters>,
+    /// Frames written
+    samples: u64,
+    /// PPS seconds inside the output, whose markers are not part of the written audio
+    pps_markers: usize,
+    uncertainty_nanos: Option<f64>,
+    sha256: String,
+    version: String,
+    command: Vec<String>,
+}
+
+/// Outputs of one directory by file name
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    files: BTreeMap<String, Entry>,
+}
+
+fn sha256(path: &Path) -> std::io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+fn load(manifest_path: &Path) -> Option<Manifest> {
+    let bytes = std::fs::read(manifest_path).ok()?;
+    match serde_json::from_slice(&bytes) {
+        Ok(manifest) => Some(manifest),
+        Err(e) => {
+            log::warn(format!(
+                "Replacing unreadable {}: {e}",
+                manifest_path.display()
+            ));
+            None
+        }
+    }
+}
+
+/// Whether every one of `paths` was completed by a cut with the same `parameters` and still
+/// has the checksum recorded for it
+pub fn done(paths: &[PathBuf], parameters: &Parameters) -> bool {
+    !paths.is_empty()
+        && paths.iter().all(|path| {
+            let Some(manifest) = load(&path.with_file_name(FILE_NAME)) else {
+                return false;
+            };
+            let name = path.file_name().unwrap().to_string_lossy();
+            manifest.files.get(name.as_ref()).is_some_and(|entry| {
+                entry.parameters.as_ref() == Some(parameters)
+                    && sha256(path).is_ok_and(|sha256| sha256 == entry.sha256)
+            })
+        })
+}
+
+/// Adds the finished output at `path` to the `manifest.json` next to it, replacing any
+/// earlier entry for the same file
+pub fn record(
+    path: &Path,
+    samples: u64,
+    timing: &Timing,
+    provenance: &Provenance,
+    parameters: Option<&Parameters>,
+) {
+    let sha256 = match sha256(path) {
+        Ok(sha256) => sha256,
+        Err(e) => {
+            log::error(Class::Io, format!("Hashing {}: {e}", path.display()));
+            return;
+        }
+    };
+    let manifest_path = path.with_file_name(FILE_NAME);
+    let mut manifest = load(&manifest_path).unwrap_or_default();
+    manifest.files.insert(
+        path.file_name().unwrap().to_string_lossy().into_owned(),
+        Entry {
+            provenance: provenance.clone(),
+            parameters: parameters.cloned(),
+            samples,
+            pps_markers: timing.pps.len(),
+            uncertainty_nanos: timing.uncertainty,
+            sha256,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            command: std::env::args().collect(),
+        },
+    );
+    let written = (|| {
+        let mut writer = BufWriter::new(File::create(&manifest_path)?);
+        serde_json::to_writer_pretty(&mut writer, &manifest)?;
+        writer.flush()
+    })();
+    if let Err(e) = written {
+        log::error(
+            Class::Io,
+            format!("Writing {}: {e}", manifest_path.display()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{Options, Writer};
+    use crate::profile::Profiles;
+    use crate::testing;
+
+    fn parameters(start: i64) -> Parameters {
+        Parameters {
+            input_dir: "in".to_owned(),
+            name_patterns: discover::PATTERNS.to_vec(),
+            clock_dir: Some("clock".to_owned()),
+            mode: "umc".to_owned(),
+            profile: Profiles::default().get("umc").unwrap().clone(),
+            pps: None,
+            start: Some(start),
+            samples: Some(4),
+            format: Format::Wav,
+            resample: false,
+            timestamps: false,
+            name_template: None,
+            comments: vec![("label".to_owned(), "hover".to_owned())],
+        }
+    }
+
+    /// Writes `path` as a cut with `parameters` would
+    fn cut(path: &Path, parameters: Parameters) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let options = Options {
+            parameters: Some(parameters),
+            ..Default::default()
+        };
+        let mut writer = Writer::create(path, spec, &options).unwrap();
+        (0..4).for_each(|s| writer.write_sample(s).unwrap());
+        writer
+            .finalize(&Timing::default(), &Provenance::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn skips_completed_outputs_on_rerun() {
+        let _serial = testing::serial();
+        let dir = testing::dir();
+        let path = dir.path().join("D1_0.wav");
+        let paths = [path.clone()];
+        assert!(!done(&paths, &parameters(0)));
+
+        cut(&path, parameters(0));
+        assert!(done(&paths, &parameters(0)));
+        assert!(!done(&paths, &parameters(1)));
+        // Relabelled or renamed outputs are cut again
+        let relabelled = Parameters {
+            comments: vec![("label".to_owned(), "pass".to_owned())],
+            ..parameters(0)
+        };
+        assert!(!done(&paths, &relabelled));
+        let renamed = Parameters {
+            name_template: Some("D{module}_{label}".to_owned()),
+            ..parameters(0)
+        };
+        assert!(!done(&paths, &renamed));
+        // Every output must be complete
+        assert!(!done(
+            &[path.clone(), dir.path().join("D1_1.wav")],
+            &parameters(0)
+        ));
+
+        // A changed output is cut again
+        std::fs::write(&path, b"edited").unwrap();
+        assert!(!done(&paths, &parameters(0)));
+        cut(&path, parameters(0));
+        assert!(done(&paths, &parameters(0)));
+    }
+
+    #[test]
+    fn records_provenance_and_checksums() {
+        let dir = testing::dir();
+        let (a, b) = (dir.path().join("D1_0.wav"), dir.path().join("D1_1.wav"));
+        std::fs::write(&a, b"abc").unwrap();
+        std::fs::write(&b, b"").unwrap();
+        let timing = Timing {
+            pps: vec![
+                crate::timing::PpsMark {
+                    sample: 0,
+                    nanos: 1
+                };
+                2
+            ],
+            uncertainty: Some(250.0),
+            ..Default::default()
+        };
+        let mut provenance = Provenance {
+            clocks: vec!["clock/1718186579700000000.csv".to_owned()],
+            requested_start: Some(5),
+            achieved_start: Some(6),
+            ..Default::default()
+        };
+        provenance.read(Path::new("in/1718186579700000000.wav"), 10, 20);
+        record(&a, 4, &timing, &provenance, Some(&parameters(0)));
+        record(&b, 0, &Timing::default(), &Provenance::default(), None);
+
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join(FILE_NAME)).unwrap()).unwrap();
+        let entry = &manifest["files"]["D1_0.wav"];
+        assert_eq!(
+            entry["sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            (entry["samples"].as_u64(), entry["pps_markers"].as_u64()),
+            (Some(4), Some(2))
+        );
+        assert_eq!(entry["uncertainty_nanos"], 250.0);
+        assert_eq!(entry["sources"][0]["file"], "in/1718186579700000000.wav");
+        assert_eq!(
+            (
+                entry["sources"][0]["start"].as_u64(),
+                entry["sources"][0]["end"].as_u64()
+            ),
+            (Some(10), Some(20))
+        );
+        assert_eq!(
+            (
+                entry["requested_start"].as_i64(),
+                entry["achieved_start"].as_i64()
+            ),
+            (Some(5), Some(6))
+        );
+        assert_eq!(entry["parameters"]["mode"], "umc");
+        assert_eq!(entry["version"], env!("CARGO_PKG_VERSION"));
+        // Recording one output keeps the others of the directory
+        assert!(manifest["files"]["D1_1.wav"]["parameters"].is_null());
+    }
+
+    #[test]
+    fn reads_parameters_from_older_manifests() {
+        let mut json = serde_json::to_value(parameters(0)).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        for field in ["name_patterns", "name_template", "comments"] {
+            fields.remove(field);
+        }
+        let read: Parameters = serde_json::from_value(json).unwrap();
+        assert_eq!(read.name_patterns, discover::PATTERNS);
+        assert!(read.name_template.is_none() && read.comments.is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::DateTime;
+
+/// Timestamps in names default to compact ISO 8601, which has no colons
+const START_FORMAT: &str = "%Y%m%dT%H%M%S%.fZ";
+/// Characters which FAT and SMB shares refuse in file names
+const UNSAFE: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+const FIELDS: [&str; 11] = [
+    "name",
+    "module",
+    "mode",
+    "flight",
+    "range",
+    "label",
+    "row",
+    "start",
+    "array",
+    "beam",
+    "beam_angle",
+];
+
+/// Values a name template can refer to; missing ones render empty
+#[derive(Clone, Debug, Default)]
+pub struct Fields {
+    /// The name the output would otherwise get
+    pub name: Option<String>,
+    pub module: Option<u8>,
+    pub mode: Option<String>,
+    pub flight: Option<String>,
+    pub range: Option<String>,
+    pub label: Option<String>,
+    /// Row of the cuts file
+    pub row: Option<usize>,
+    /// UTC nanos of the first sample
+    pub start: Option<i64>,
+    /// I2S microphone row, 1 or 2
+    pub array: Option<u8>,
+    /// I2S beam index
+    pub beam: Option<usize>,
+    /// I2S beam steering as the delay between microphones in samples
+    pub beam_angle: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Text(String),
+    /// Placeholder name and, for `start`, a strftime format
+    Field(&'static str, Option<String>),
+}
+
+/// An output file name such as `{flight}/D{module}_{start:%Y%m%dT%H%M%S}`, without extension.
+/// `/` creates subdirectories; `{{` and `}}` are literal braces.
+#[derive(Clone, Debug)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        return Err(format!("unclosed '{{' in '{s}'"));
+                    };
+                    let (field, format) = match rest[..end].split_once(':') {
+                        Some((field, format)) => (field, Some(format.to_owned())),
+                        None => (&rest[..end], None),
+                    };
+                    let Some(field) = FIELDS.iter().find(|f| **f == field) else {
+                        return Err(format!(
+                            "unknown placeholder '{{{field}}}', expected one of {}",
+                            FIELDS.join(", ")
+                        ));
+                    };
+                    if let Some(format) = &format {
+                        if *field != "start" {
+                            return Err(format!("only {{start}} takes a format, not {{{field}}}"));
+                        }
+                        if StrftimeItems::new(format).any(|i| matches!(i, Item::Error)) {
+                            return Err(format!("invalid time format '{format}'"));
+                        }
+                    }
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                    parts.push(Part::Field(field, format));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(format!("unmatched '}}' in '{s}'")),
+                c if UNSAFE.contains(&c) || c.is_control() => {
+                    return Err(format!("'{c}' is not safe in file names"));
+                }
+                c => text.push(c),
+            }
+        }
+        parts.push(Part::Text(text));
+        Ok(Template {
+            source: s.to_owned(),
+            parts,
+        })
+    }
+
+    /// The template as written
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the template refers to `field`
+    pub fn uses(&self, field: &str) -> bool {
+        self.parts
+            .iter()
+            .any(|p| matches!(p, Part::Field(f, _) if *f == field))
+    }
+
+    pub fn render(&self, fields: &Fields) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Text(text) => {
+                    name.push_str(text);
+                    continue;
+                }
+                Part::Field(field, format) => match *field {
+                    "name" => fields.name.clone(),
+                    "module" => fields.module.map(|m| m.to_string()),
+                    "mode" => fields.mode.clone(),
+                    "flight" => fields.flight.clone(),
+                    "range" => fields.range.clone(),
+                    "label" => fields.label.clone(),
+                    "row" => fields.row.map(|r| r.to_string()),
+                    "start" => fields.start.map(|nanos| {
+                        let format = format.as_deref().unwrap_or(START_FORMAT);
+                        DateTime::from_timestamp_nanos(nanos)
+                            .format(format)
+                            .to_string()
+                    }),
+                    "array" => fields.array.map(|a| a.to_string()),
+                    "beam" => fields.beam.map(|b| b.to_string()),
+                    "beam_angle" => fields.beam_angle.map(|a| format!("{a:+}")),
+                    _ => unreachable!("checked in parse"),
+                },
+            };
+            name.push_str(&safe(&value.unwrap_or_default()));
+        }
+        name
+    }
+}
+
+/// `value` with separators and characters unsafe on FAT and SMB shares replaced by `-`
+fn safe(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if UNSAFE.contains(&c) || c == '/' || c.is_control() {
+                '-'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Name of an output, from `template` if given or else from `default`
+pub fn file_name(template: Option<&Template>, default: &str, fields: &Fields) -> String {
+    match template {
+        Some(template) => template.render(fields),
+        None => Template::parse(default).unwrap().render(fields),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Fields {
+        Fields {
+            module: Some(3),
+            flight: Some("f1/a".to_owned()),
+            row: Some(7),
+            start: Some(1_718_186_579_700_000_000),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let template = Template::parse("{flight}/D{module}_{row}_{start}").unwrap();
+        // Separators in values do not create directories
+        assert_eq!(template.render(&fields()), "f1-a/D3_7_20240612T100259.700Z");
+        let template = Template::parse("{{{label}}}_{start:%H:%M:%S}").unwrap();
+        assert_eq!(template.render(&fields()), "{}_10-02-59");
+        assert!(template.uses("start"));
+        assert!(!template.uses("module"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for (template, error) in [
+            ("D{module", "unclosed"),
+            ("D{module}}", "unmatched"),
+            ("D}", "unmatched"),
+            ("{modul}", "unknown placeholder"),
+            ("{module:%H}", "only {start} takes a format"),
+            ("{start:%Q}", "invalid time format"),
+            ("a:b", "not safe"),
+            ("a\tb", "not safe"),
+        ] {
+            let e = Template::parse(template).unwrap_err();
+            assert!(e.contains(error), "{template}: {e}");
+        }
+    }
+}
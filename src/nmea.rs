@@ -0,0 +1,261 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{NaiveDate, NaiveTime};
+
+use crate::discover::{self, NamePattern};
+use crate::index::Index;
+use crate::log::{self, Class};
+use crate::Record;
+
+/// A time fix from an RMC or ZDA sentence, with the host time it was logged at
+struct Fix {
+    host: f64,
+    nanos: i64,
+}
+
+/// Validates the `*hh` checksum and returns the fields of a sentence
+fn fields(sentence: &str) -> Option<Vec<&str>> {
+    let body = sentence.strip_prefix('$')?;
+    let (body, checksum) = match body.split_once('*') {
+        Some((body, checksum)) => (body, Some(checksum.trim())),
+        None => (body.trim_end(), None),
+    };
+    if let Some(checksum) = checksum {
+        let sum = body.bytes().fold(0u8, |a, b| a ^ b);
+        if u8::from_str_radix(checksum, 16).ok()? != sum {
+            return None;
+        }
+    }
+    Some(body.split(',').collect())
+}
+
+/// UTC nanos of `hhmmss[.ss]` on `date`, truncated to the second. 23:59:60 falls on
+/// the following midnight, as in POSIX time.
+fn to_nanos(date: NaiveDate, time: &str) -> Option<i64> {
+    let h = time.get(0..2)?.parse().ok()?;
+    let m = time.get(2..4)?.parse().ok()?;
+    let s: u32 = time.get(4..6)?.parse().ok()?;
+    let leap = s == 60;
+    let time = NaiveTime::from_hms_opt(h, m, if leap { 59 } else { s })?;
+    let nanos = date.and_time(time).and_utc().timestamp_nanos_opt()?;
+    Some(nanos + if leap { 1_000_000_000 } else { 0 })
+}
+
+/// Time of a valid RMC or ZDA sentence
+fn parse_sentence(sentence: &str) -> Option<i64> {
+    let f = fields(sentence)?;
+    match f[0].get(2..)? {
+        // $xxRMC,hhmmss.ss,A,lat,N,lon,E,speed,course,ddmmyy,...
+        "RMC" if f.len() > 9 && f[2] == "A" => {
+            let d = f[9];
+            let date = NaiveDate::from_ymd_opt(
+                2000 + d.get(4..6)?.parse::<i32>().ok()?,
+                d.get(2..4)?.parse().ok()?,
+                d.get(0..2)?.parse().ok()?,
+            )?;
+            to_nanos(date, f[1])
+        }
+        // $xxZDA,hhmmss.ss,dd,mm,yyyy,zh,zm
+        "ZDA" if f.len() > 4 => {
+            let date = NaiveDate::from_ymd_opt(
+                f[4].parse().ok()?,
+                f[3].parse().ok()?,
+                f[2].parse().ok()?,
+            )?;
+            to_nanos(date, f[1])
+        }
+        _ => None,
+    }
+}
+
+/// Splits `<host seconds> <rest>` on the first comma or whitespace
+fn host_time(line: &str) -> Option<(f64, &str)> {
+    let (host, rest) = line.split_once(|c: char| c == ',' || c.is_whitespace())?;
+    Some((host.parse().ok()?, rest.trim()))
+}
+
+/// Reads `<host seconds> $..RMC/ZDA,...` lines; `leap_seconds` is subtracted when the
+/// receiver reports GPS time instead of UTC.
+fn read_nmea(path: &Path, leap_seconds: i64) -> Vec<Fix> {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        log::error(
+            Class::Input,
+            format!("Failed to read NMEA log {}", path.display()),
+        );
+        return Vec::new();
+    };
+    let mut fixes = text
+        .lines()
+        .filter_map(host_time)
+        .filter_map(|(host, sentence)| {
+            parse_sentence(sentence).map(|nanos| Fix {
+                host,
+                nanos: nanos - leap_seconds * 1_000_000_000,
+            })
+        })
+        .collect::<Vec<_>>();
+    fixes.sort_by(|a, b| a.host.total_cmp(&b.host));
+    fixes
+}
+
+/// Reads `<host seconds> <sample counter>` lines of PPS edges
+fn read_pps(path: &Path) -> Vec<(f64, u64)> {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        log::error(
+            Class::Input,
+            format!("Failed to read PPS log {}", path.display()),
+        );
+        return Vec::new();
+    };
+    text.lines()
+        .filter_map(host_time)
+        .filter_map(|(host, sample)| Some((host, sample.parse().ok()?)))
+        .collect()
+}
+
+/// Writes a clock csv for the recording in `input_dir`, the first named by `patterns`,
+/// labelling every PPS edge with the first NMEA time logged within the second after it.
+pub fn import<P: std::convert::AsRef<Path>>(
+    input_dir: P,
+    patterns: &[NamePattern],
+    nmea: P,
+    pps: P,
+    clock_dir: P,
+    leap_seconds: i64,
+) {
+    let fixes = read_nmea(nmea.as_ref(), leap_seconds);
+    let edges = read_pps(pps.as_ref());
+    if fixes.is_empty() || edges.is_empty() {
+        log::error(Class::Input, "No NMEA times or PPS edges found");
+        return;
+    }
+
+    let waves = discover::recordings(input_dir.as_ref(), patterns);
+    let Some(first) = waves.first().map(|r| &r.path) else {
+        log::error(Class::Input, "No recordings found in input dir");
+        return;
+    };
+    let clock = PathBuf::from(clock_dir.as_ref())
+        .join(first.file_stem().unwrap())
+        .with_extension("csv");
+
+    // Recording-wide frame at which each file starts
+    let mut index = Index::open(input_dir.as_ref());
+    let mut offset = 0u64;
+    let files = waves
+        .iter()
+        .filter_map(|r| {
+            let f = &r.path;
+            let (duration, _) = index.wav_info(f)?;
+            let start = offset;
+            offset += duration as u64;
+            Some((start, offset, f.file_name()?.to_str()?.to_owned()))
+        })
+        .collect::<Vec<_>>();
+    index.save();
+
+    let mut records: Vec<Record> = Vec::new();
+    let (mut unmatched, mut outside, mut repeated) = (0, 0, 0);
+    for (host, sample) in edges {
+        let i = fixes.partition_point(|f| f.host <= host);
+        let Some(fix) = fixes.get(i).filter(|f| f.host < host + 1.0) else {
+            unmatched += 1;
+            continue;
+        };
+        let Some((start, _, file)) = files.iter().find(|(s, e, _)| (*s..*e).contains(&sample))
+        else {
+            outside += 1;
+            continue;
+        };
+        // A leap second shares its POSIX time with the following second; keep the first
+        if records.last().is_some_and(|r| r.time >= fix.nanos) {
+            repeated += 1;
+            continue;
+        }
+        records.push(Record {
+            time: fix.nanos,
+            sample,
+            file_sample: (sample - start) as u32,
+            file: file.clone(),
+        });
+    }
+    if unmatched + outside + repeated > 0 {
+        log::warn(format!(
+            "Skipped PPS edges: {unmatched} without an NMEA time, {outside} outside the recording, {repeated} repeating a time"
+        ));
+    }
+    if records.is_empty() {
+        log::error(Class::Input, "No PPS edges could be timed");
+        return;
+    }
+
+    let written = (|| {
+        std::fs::create_dir_all(clock_dir.as_ref())?;
+        let mut writer = csv::Writer::from_path(&clock)?;
+        for r in &records {
+            writer.serialize(r)?;
+        }
+        writer.flush()?;
+        Ok::<_, csv::Error>(())
+    })();
+    match written {
+        Ok(()) => log::info(format!(
+            "Wrote {} records to {}",
+            records.len(),
+            clock.display()
+        )),
+        Err(e) => log::error(Class::Io, format!("Writing {}: {e}", clock.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NANOS: i64 = 1_718_186_580_000_000_000;
+
+    #[test]
+    fn parses_rmc() {
+        let rmc = "$GPRMC,100300.00,A,4807.038,N,01131.000,E,0.0,0.0,120624,,,A*5D";
+        assert_eq!(parse_sentence(rmc), Some(NANOS));
+        // Without a checksum the sentence is taken as is
+        assert_eq!(parse_sentence(rmc.split('*').next().unwrap()), Some(NANOS));
+    }
+
+    #[test]
+    fn rejects_bad_sentences() {
+        // Wrong checksum
+        let rmc = "$GPRMC,100300.00,A,4807.038,N,01131.000,E,0.0,0.0,120624,,,A*5E";
+        assert_eq!(parse_sentence(rmc), None);
+        // No fix
+        assert_eq!(
+            parse_sentence("$GPRMC,100300.00,V,,,,,,,120624,,,N*7C"),
+            None
+        );
+        assert_eq!(parse_sentence("$GPGGA,100300.00,4807.038,N"), None);
+        assert_eq!(parse_sentence("GPRMC,100300.00"), None);
+    }
+
+    #[test]
+    fn parses_zda() {
+        assert_eq!(
+            parse_sentence("$GNZDA,100300.00,12,06,2024,00,00*7B"),
+            Some(NANOS)
+        );
+        // A leap second falls on the following midnight
+        assert_eq!(
+            parse_sentence("$GPZDA,235960.00,31,12,2016,00,00*69"),
+            Some(1_483_228_800_000_000_000)
+        );
+    }
+
+    #[test]
+    fn splits_host_time() {
+        assert_eq!(
+            host_time("1718186580.25 $GNZDA,..."),
+            Some((1718186580.25, "$GNZDA,..."))
+        );
+        assert_eq!(host_time("12.5,4800"), Some((12.5, "4800")));
+        assert_eq!(host_time("$GNZDA,..."), None);
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::timing::{PpsMark, Timing};
+
+const MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+/// Total header size reserved up front so the shape can be patched in on finalize
+const HEADER_LEN: usize = 128;
+
+/// Writes samples as a NumPy `.npy` array of shape `[samples, channels]` with a JSON sidecar.
+pub struct NpyWriter {
+    out: BufWriter<File>,
+    sidecar: PathBuf,
+    spec: hound::WavSpec,
+    float: bool,
+    comments: Vec<(String, String)>,
+    written: u64,
+}
+
+#[derive(serde::Serialize)]
+struct Sidecar<'a> {
+    start_nanos: Option<i64>,
+    sample_rate: u32,
+    channels: u16,
+    samples: u64,
+    dtype: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uncertainty_nanos: Option<f64>,
+    #[serde(flatten)]
+    tags: BTreeMap<&'a str, &'a str>,
+    pps: &'a [PpsMark],
+}
+
+impl NpyWriter {
+    /// Writes the array to `path` and, on finalizing, its metadata to `sidecar`
+    pub fn create<P: std::convert::AsRef<Path>>(
+        path: P,
+        sidecar: PathBuf,
+        spec: hound::WavSpec,
+        float: bool,
+        comments: &[(String, String)],
+    ) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&[0; HEADER_LEN])?;
+        Ok(Self {
+            out,
+            sidecar,
+            spec,
+            float,
+            comments: comments.to_vec(),
+            written: 0,
+        })
+    }
+
+    fn dtype(&self) -> &'static str {
+        if self.float {
+            "<f4"
+        } else {
+            "<i4"
+        }
+    }
+
+    pub fn write_sample(&mut self, sample: i32) -> io::Result<()> {
+        if self.float {
+            let sample = sample as f32 / -(i32::MIN as f32);
+            self.out.write_all(&sample.to_le_bytes())?;
+        } else {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn finalize(mut self, timing: &Timing) -> io::Result<()> {
+        let channels = self.spec.channels as u64;
+        let frames = self.written / channels;
+
+        let dict = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': ({frames}, {channels}), }}",
+            self.dtype()
+        );
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&((HEADER_LEN - MAGIC.len() - 2) as u16).to_le_bytes());
+        header.extend_from_slice(dict.as_bytes());
+        header.resize(HEADER_LEN - 1, b' ');
+        header.push(b'\n');
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header)?;
+        self.out.flush()?;
+
+        let sidecar = Sidecar {
+            start_nanos: timing.start_nanos,
+            sample_rate: self.spec.sample_rate,
+            channels: self.spec.channels,
+            samples: frames,
+            dtype: self.dtype(),
+            uncertainty_nanos: timing.uncertainty,
+            tags: self
+                .comments
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect(),
+            pps: &timing.pps,
+        };
+        let mut out = BufWriter::new(File::create(&self.sidecar)?);
+        serde_json::to_writer_pretty(&mut out, &sidecar)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// The header dict and data of a `.npy` file
+    fn read(path: &Path) -> (String, Vec<u8>) {
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(&bytes[..MAGIC.len()], MAGIC);
+        let len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + len) % 16, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + len]).unwrap();
+        assert!(header.ends_with('\n'));
+        (header.trim_end().to_owned(), bytes[10 + len..].to_vec())
+    }
+
+    #[test]
+    fn writes_samples_by_channel_with_a_sidecar() {
+        let dir = testing::dir();
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let samples = [0, -1, i32::MIN, -(1 << 30), 1 << 30, 7];
+        let timing = Timing {
+            start_nanos: Some(1_718_186_580_000_000_000),
+            pps: vec![PpsMark {
+                sample: 1,
+                nanos: 1_718_186_580_000_020_833,
+            }],
+            uncertainty: None,
+        };
+        let comments = [("module".to_owned(), "3".to_owned())];
+        for float in [false, true] {
+            let path = dir.path().join(format!("cut-{float}.npy"));
+            let sidecar = path.with_extension("json");
+            let mut writer =
+                NpyWriter::create(&path, sidecar.clone(), spec, float, &comments).unwrap();
+            samples
+                .iter()
+                .for_each(|s| writer.write_sample(*s).unwrap());
+            writer.finalize(&timing).unwrap();
+
+            let (header, data) = read(&path);
+            let dtype = if float { "<f4" } else { "<i4" };
+            assert_eq!(
+                header,
+                format!("{{'descr': '{dtype}', 'fortran_order': False, 'shape': (3, 2), }}")
+            );
+            let words = data
+                .chunks(4)
+                .map(|w| w.try_into().unwrap())
+                .collect::<Vec<[u8; 4]>>();
+            if float {
+                let values = words
+                    .into_iter()
+                    .map(f32::from_le_bytes)
+                    .collect::<Vec<_>>();
+                let scale = 2147483648.0;
+                assert_eq!(values, [0.0, -1.0 / scale, -1.0, -0.5, 0.5, 7.0 / scale]);
+            } else {
+                let values = words
+                    .into_iter()
+                    .map(i32::from_le_bytes)
+                    .collect::<Vec<_>>();
+                assert_eq!(values, samples);
+            }
+
+            let json: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(&sidecar).unwrap()).unwrap();
+            assert_eq!(json["start_nanos"], 1_718_186_580_000_000_000i64);
+            assert_eq!(
+                (json["sample_rate"].as_u64(), json["samples"].as_u64()),
+                (Some(48000), Some(3))
+            );
+            assert_eq!(json["dtype"], dtype);
+            assert_eq!(json["module"], "3");
+            assert_eq!(json["pps"][0]["sample"], 1);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::flac::FlacWriter;
+use crate::log::{self, Class};
+use crate::manifest::{self, Parameters, Provenance};
+use crate::name::{Fields, Template};
+use crate::npy::NpyWriter;
+use crate::timing::Timing;
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// 32-bit integer WAV
+    #[default]
+    Wav,
+    /// Lossless FLAC (at most 24 bits per sample)
+    Flac,
+    /// NumPy int32 array with a JSON sidecar
+    Npy,
+    /// NumPy float32 array scaled to [-1, 1) with a JSON sidecar
+    NpyFloat,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Wav => "wav",
+            Format::Flac => "flac",
+            Format::Npy | Format::NpyFloat => "npy",
+        }
+    }
+}
+
+/// Settings shared by every output file of a job
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub format: Format,
+    /// Metadata stored as tags in formats which support them
+    pub comments: Vec<(String, String)>,
+    /// Write a `.pps.csv` sidecar mapping output samples to UTC nanos at every PPS
+    pub timestamps: bool,
+    /// Resample onto an exact UTC grid at the nominal rate, following the clock records
+    pub resample: bool,
+    /// Warn when a cut's estimated timing uncertainty exceeds this many nanos
+    pub max_uncertainty: Option<i64>,
+    /// Names the outputs instead of each command's default
+    pub name_template: Option<Template>,
+    /// What the name template can refer to
+    pub fields: Fields,
+    /// Recorded in the manifest so reruns can skip completed outputs
+    pub parameters: Option<Parameters>,
+}
+
+enum Sink {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Flac(Box<FlacWriter>),
+    Npy(NpyWriter),
+    /// Writing failed and the `.part` file was deleted
+    Failed,
+}
+
+/// Audio output in any of the supported formats. Written under a `.part` name and renamed
+/// into place once finished, so an interrupted or failed job never leaves a truncated output.
+pub struct Writer {
+    sink: Sink,
+    path: PathBuf,
+    part: PathBuf,
+    timestamps: bool,
+    parameters: Option<Parameters>,
+    channels: u16,
+    written: u64,
+    /// Errors logged before the output was created; any more mean its job failed
+    errors: usize,
+}
+
+impl Writer {
+    /// Creates the output under its `.part` name. Failures are logged as I/O errors.
+    pub fn create<P: std::convert::AsRef<Path>>(
+        path: P,
+        spec: hound::WavSpec,
+        options: &Options,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut part = path.file_name().unwrap_or(path.as_os_str()).to_os_string();
+        part.push(".part");
+        let part = path.with_file_name(part);
+        let sink = open(&path, &part, spec, options).inspect_err(|e| {
+            log::error(Class::Io, format!("Creating {}: {e}", path.display()));
+            remove(&part);
+        })?;
+        Ok(Self {
+            sink,
+            path,
+            part,
+            timestamps: options.timestamps,
+            parameters: options.parameters.clone(),
+            channels: spec.channels,
+            written: 0,
+            errors: log::errors(),
+        })
+    }
+
+    /// Writes one sample. After a failure, which is logged as an I/O error and deletes the
+    /// `.part` file, every further sample fails too.
+    pub fn write_sample(&mut self, sample: i32) -> io::Result<()> {
+        let result = match &mut self.sink {
+            Sink::Wav(w) => w.write_sample(sample).map_err(hound_io),
+            Sink::Flac(w) => w.write_sample(sample),
+            Sink::Npy(w) => w.write_sample(sample),
+            Sink::Failed => return Err(io::ErrorKind::BrokenPipe.into()),
+        };
+        match result {
+            Ok(()) => {
+                self.written += 1;
+                Ok(())
+            }
+            Err(e) => {
+                log::error(Class::Io, format!("Writing {}: {e}", self.part.display()));
+                self.sink = Sink::Failed;
+                remove(&self.part);
+                Err(e)
+            }
+        }
+    }
+
+    /// Finishes the output, writes `timing` into whichever sidecars are enabled and records
+    /// the output in the manifest of its directory. If its job logged an error the output is
+    /// incomplete, so it is deleted instead and any earlier output of the same name is kept.
+    /// Failures are logged as I/O errors.
+    pub fn finalize(self, timing: &Timing, provenance: &Provenance) -> io::Result<()> {
+        if log::errors() > self.errors {
+            let failed = matches!(self.sink, Sink::Failed);
+            self.discard();
+            if failed {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            return Ok(());
+        }
+        let finished = match self.sink {
+            Sink::Wav(w) => w.finalize().map_err(hound_io),
+            Sink::Flac(w) => w.finalize(),
+            Sink::Npy(w) => w.finalize(timing),
+            Sink::Failed => unreachable!("a failed write is logged"),
+        }
+        .and_then(|()| {
+            if self.timestamps {
+                write_pps(&self.path.with_extension("pps.csv"), timing)
+            } else {
+                Ok(())
+            }
+        })
+        .and_then(|()| std::fs::rename(&self.part, &self.path));
+        if let Err(e) = finished {
+            log::error(Class::Io, format!("Finishing {}: {e}", self.path.display()));
+            remove(&self.part);
+            return Err(e);
+        }
+        let frames = self.written / self.channels as u64;
+        log::debug(format!("Wrote {} ({frames} frames)", self.path.display()));
+        manifest::record(
+            &self.path,
+            frames,
+            timing,
+            provenance,
+            self.parameters.as_ref(),
+        );
+        Ok(())
+    }
+
+    /// Deletes the unfinished output, keeping any earlier output of the same name
+    pub fn discard(self) {
+        drop(self.sink);
+        match std::fs::remove_file(&self.part) {
+            Ok(()) => log::warn(format!("Discarded incomplete {}", self.path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::warn(format!("Removing {}: {e}", self.part.display())),
+        }
+    }
+}
+
+fn open(path: &Path, part: &Path, spec: hound::WavSpec, options: &Options) -> io::Result<Sink> {
+    // Name templates may place outputs in subdirectories
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let comments = &options.comments;
+    let sidecar = path.with_extension("json");
+    Ok(match options.format {
+        Format::Wav => Sink::Wav(hound::WavWriter::create(part, spec).map_err(hound_io)?),
+        Format::Flac => Sink::Flac(Box::new(FlacWriter::create(part, spec, comments)?)),
+        Format::Npy => Sink::Npy(NpyWriter::create(part, sidecar, spec, false, comments)?),
+        Format::NpyFloat => Sink::Npy(NpyWriter::create(part, sidecar, spec, true, comments)?),
+    })
+}
+
+fn write_pps(path: &Path, timing: &Timing) -> io::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_path(path)?;
+    writer.write_record(["sample", "nanos"])?;
+    for pps in &timing.pps {
+        writer.serialize(pps)?;
+    }
+    writer.flush()
+}
+
+/// Deletes a `.part` file left by a failed output, if there is one
+fn remove(part: &Path) {
+    match std::fs::remove_file(part) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => log::warn(format!("Removing {}: {e}", part.display())),
+    }
+}
+
+fn hound_io(e: hound::Error) -> io::Error {
+    match e {
+        hound::Error::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn spec() -> hound::WavSpec {
+        hound::WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+
+    #[test]
+    fn failed_outputs_are_logged_and_leave_no_part_file() {
+        let _serial = testing::serial();
+        let dir = testing::dir();
+        // A file where the output directory should be
+        std::fs::write(dir.path().join("taken"), b"").unwrap();
+        for format in [Format::Wav, Format::Flac, Format::Npy] {
+            let options = Options {
+                format,
+                ..Default::default()
+            };
+            let errors = log::errors();
+            let path = dir.path().join("taken/cut.wav");
+            assert!(Writer::create(&path, spec(), &options).is_err());
+            assert_eq!(log::errors(), errors + 1);
+        }
+        let names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["taken"]);
+    }
+
+    #[test]
+    fn outputs_appear_once_finished() {
+        let _serial = testing::serial();
+        let dir = testing::dir();
+        let path = dir.path().join("cut.wav");
+        let mut writer = Writer::create(&path, spec(), &Options::default()).unwrap();
+        (0..10).for_each(|s| writer.write_sample(s).unwrap());
+        assert!(!path.exists());
+        assert!(dir.path().join("cut.wav.part").exists());
+        writer
+            .finalize(&Timing::default(), &Provenance::default())
+            .unwrap();
+        assert!(!dir.path().join("cut.wav.part").exists());
+        assert_eq!(hound::WavReader::open(&path).unwrap().duration(), 10);
+    }
+
+    #[test]
+    fn writes_the_pps_sidecar() {
+        let _serial = testing::serial();
+        let dir = testing::dir();
+        let path = dir.path().join("cut.wav");
+        let options = Options {
+            timestamps: true,
+            ..Default::default()
+        };
+        let mut writer = Writer::create(&path, spec(), &options).unwrap();
+        (0..10).for_each(|s| writer.write_sample(s).unwrap());
+        let pps = [
+            (2, 1_718_186_580_000_000_000),
+            (7, 1_718_186_581_000_000_000),
+        ];
+        let timing = Timing {
+            pps: pps
+                .map(|(sample, nanos)| crate::timing::PpsMark { sample, nanos })
+                .to_vec(),
+            ..Default::default()
+        };
+        writer.finalize(&timing, &Provenance::default()).unwrap();
+        let csv = std::fs::read_to_string(dir.path().join("cut.pps.csv")).unwrap();
+        assert_eq!(
+            csv,
+            "sample,nanos\n2,1718186580000000000\n7,1718186581000000000\n"
+        );
+    }
+}
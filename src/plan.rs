@@ -0,0 +1,412 @@
+use std::path::{Path, PathBuf};
+
+use crate::clock::{self, Session};
+use crate::discover::{self, NamePattern};
+use crate::index::Index;
+use crate::log::{self, Class, Outcome};
+use crate::pps::{self, PpsLayout};
+use crate::profile::DeviceProfile;
+
+/// What a cut or concat would write, resolved without reading any audio
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub output: String,
+    /// Input files in the order they would be read
+    pub sources: Vec<String>,
+    /// Input file and frame the output would start at
+    pub start: Option<(String, u32)>,
+    pub samples: u64,
+    pub notes: Vec<String>,
+}
+
+impl Plan {
+    pub fn new(output: &str) -> Self {
+        Self {
+            output: output.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn warn(&mut self, message: impl std::fmt::Display) {
+        self.notes.push(format!("warning: {message}"));
+    }
+
+    pub fn error(&mut self, message: impl std::fmt::Display) {
+        self.notes.push(format!("error: {message}"));
+    }
+
+    /// Follows the files from frame `seek` of `start_file` the way a cut reads them, until
+    /// `samples` output samples are covered or `end_file` is done. Returns the samples covered.
+    #[allow(clippy::too_many_arguments)]
+    fn walk(
+        &mut self,
+        waves: &[PathBuf],
+        start_file: &Path,
+        mut seek: u32,
+        end_file: Option<&Path>,
+        samples: u64,
+        per_frame: f64,
+        index: &mut Index,
+    ) -> u64 {
+        let mut remaining = samples;
+        let mut first = true;
+        for wav in waves
+            .iter()
+            .skip_while(|w| w.file_name() != start_file.file_name())
+        {
+            let name = wav.file_name().unwrap().to_string_lossy().into_owned();
+            let Some((duration, _)) = index.wav_info(wav) else {
+                self.error(format!("cannot read {name}"));
+                break;
+            };
+            if first {
+                if seek > duration {
+                    seek -= duration;
+                    continue;
+                }
+                self.start.get_or_insert((name.clone(), seek));
+                first = false;
+            } else {
+                seek = 0;
+            }
+            let available = ((duration - seek) as f64 * per_frame) as u64;
+            remaining -= remaining.min(available);
+            self.sources.push(name);
+            if remaining == 0 || end_file.is_some_and(|end| end.file_name() == wav.file_name()) {
+                break;
+            }
+        }
+        samples - remaining
+    }
+}
+
+/// Plan of an output which is already complete and would be skipped
+pub fn skip(output: &str) -> Plan {
+    let mut plan = Plan::new(output);
+    plan.notes
+        .push("skipped: already cut with the same parameters".to_owned());
+    plan
+}
+
+/// Logs a problem with `output` and counts it as failed, or reports it in its plan on a dry run
+pub fn fail(plans: &mut Option<Vec<Plan>>, output: &str, class: Class, message: &str) {
+    match plans {
+        Some(plans) => {
+            let mut plan = Plan::new(output);
+            plan.error(message);
+            plans.push(plan);
+        }
+        None => {
+            log::error(class, message);
+            log::outcome(Outcome::Failed);
+        }
+    }
+}
+
+/// Plan of a cut timed by clock csvs, stitched across `sessions` like `umc::make_wav`
+#[allow(clippy::too_many_arguments)]
+pub fn clock(
+    output: &str,
+    input_dir: &Path,
+    patterns: &[NamePattern],
+    sessions: &[&Session],
+    start: Option<i64>,
+    samples: Option<u64>,
+    profile: &DeviceProfile,
+    index: &mut Index,
+) -> Plan {
+    let mut plan = Plan::new(output);
+    let waves = discover::waves(input_dir, patterns);
+    let Some(first) = sessions.first() else {
+        plan.error("requested start not in audio data time range");
+        return plan;
+    };
+    let rate = profile.rate();
+    let per_frame = profile.samples_per_frame();
+    let channels = profile.output_spec().channels as u64;
+    plan.samples = samples.unwrap_or(first.records[first.records.len() - 1].sample);
+
+    let mut nanos = start.unwrap_or(first.start);
+    let mut remaining = plan.samples;
+    for session in sessions {
+        if remaining == 0 {
+            break;
+        }
+        let records = &session.records;
+        if !waves
+            .iter()
+            .any(|w| w.file_stem().is_some_and(|s| s == session.name()))
+        {
+            plan.warn(format!(
+                "clock start not found for session {}",
+                session.name()
+            ));
+            continue;
+        }
+        if nanos < session.start {
+            let gap = ((session.start - nanos) as f64 * rate / 1e9).round() as u64;
+            let gap = (gap * channels).min(remaining);
+            plan.warn(format!(
+                "{} frames of silence before session {}",
+                gap / channels,
+                session.name()
+            ));
+            remaining -= gap;
+            nanos = session.start;
+        }
+        if nanos > session.end {
+            continue;
+        }
+        let Some(fix) = clock::locate(records, &session.rates, nanos, rate) else {
+            continue;
+        };
+        let r = &records[fix.record];
+        let seek = (r.file_sample as f64 + fix.sample - r.sample as f64)
+            .round()
+            .max(0.0) as u32;
+        let end_file = input_dir.join(&records[records.len() - 1].file);
+        let covered = plan.walk(
+            &waves,
+            &input_dir.join(&r.file),
+            seek,
+            Some(&end_file),
+            remaining,
+            per_frame,
+            index,
+        );
+        remaining -= covered;
+        nanos += (covered as f64 / per_frame / rate * 1e9).round() as i64;
+    }
+    if plan.start.is_none() {
+        plan.error("requested start not in audio data time range");
+    } else if remaining > 0 {
+        plan.warn(format!(
+            "recording ends {remaining} samples before the requested end"
+        ));
+    }
+    plan
+}
+
+/// Plan of a cut located with the PPS markers in the audio, like `umc::make_wav_pps`
+#[allow(clippy::too_many_arguments)]
+pub fn pps(
+    output: &str,
+    input_dir: &Path,
+    patterns: &[NamePattern],
+    start: i64,
+    samples: u64,
+    profile: &DeviceProfile,
+    layout: &PpsLayout,
+    index: &mut Index,
+) -> Plan {
+    let mut plan = Plan::new(output);
+    plan.samples = samples;
+    let (markers, waves) = pps::find_best(input_dir, patterns, start, layout, index);
+    let Some(best) = markers.iter().min_by_key(|p| (p.nanos - start).abs()) else {
+        plan.error("no PPS markers found near requested start");
+        return plan;
+    };
+    let Some((_, in_spec)) = index.wav_info(&best.file) else {
+        plan.error(format!("cannot read {}", best.file.display()));
+        return plan;
+    };
+    let Some((start_file, seek)) = pps::find_start(
+        start,
+        best.nanos,
+        best.sample,
+        &best.file,
+        &waves,
+        in_spec.channels as u32,
+        profile.rate(),
+        index,
+    ) else {
+        plan.error("requested start not in audio data time range");
+        return plan;
+    };
+    let per_frame = profile.samples_per_frame();
+    let covered = plan.walk(&waves, &start_file, seek, None, samples, per_frame, index);
+    if covered < samples {
+        plan.warn(format!(
+            "recording ends {} samples before the requested end",
+            samples - covered
+        ));
+    }
+    plan
+}
+
+/// Prints `plans` as a table on stdout
+pub fn print(plans: &[Plan]) {
+    let rows = plans
+        .iter()
+        .map(|p| {
+            let sources = match &p.sources[..] {
+                [] => "-".to_owned(),
+                [one] => one.clone(),
+                [first, .., last] => format!("{first} .. {last} ({} files)", p.sources.len()),
+            };
+            let start = p
+                .start
+                .as_ref()
+                .map_or("-".to_owned(), |(file, frame)| format!("{file}:{frame}"));
+            [
+                p.output.clone(),
+                sources,
+                start,
+                p.samples.to_string(),
+                p.notes.join("; "),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["OUTPUT", "SOURCES", "START", "SAMPLES", "NOTES"].map(str::to_owned);
+    let mut widths = [0; 4];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        println!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {:>w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        );
+    }
+    let errors = plans
+        .iter()
+        .filter(|p| p.notes.iter().any(|n| n.starts_with("error")))
+        .count();
+    println!("{} outputs planned, {errors} with errors", plans.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Decoder;
+    use crate::testing;
+    use crate::Record;
+
+    const NANOS: i64 = 1_718_186_580_000_000_000;
+    const STARTS: [i64; 3] = [NANOS - 1_000_000_000, NANOS, NANOS + 1_000_000_000];
+
+    fn profile() -> DeviceProfile {
+        DeviceProfile {
+            sample_rate: 48000,
+            channels: 1,
+            step: 1,
+            tag_bits: 0,
+            pps: Some(PpsLayout::default()),
+            decoder: Decoder::Channels,
+        }
+    }
+
+    fn names(plan: &Plan) -> Vec<&str> {
+        plan.sources.iter().map(|s| &s[..19]).collect()
+    }
+
+    #[test]
+    fn plans_cuts_timed_by_a_clock() {
+        let dir = testing::dir();
+        let waves = testing::recordings(dir.path(), &STARTS, None);
+        let records = waves
+            .iter()
+            .zip(STARTS)
+            .enumerate()
+            .map(|(i, (wave, time))| Record {
+                time,
+                sample: i as u64 * 48000,
+                file_sample: 0,
+                file: wave.file_name().unwrap().to_str().unwrap().to_owned(),
+            })
+            .collect::<Vec<_>>();
+        let session = Session {
+            clock: dir.path().join(format!("{}.csv", STARTS[0])),
+            rates: clock::interval_rates(&records),
+            records,
+            start: STARTS[0],
+            end: STARTS[2] + 1_000_000_000,
+        };
+        let mut index = Index::open(dir.path());
+        let mut plan = |start, samples| {
+            let (dir, patterns) = (dir.path(), &discover::PATTERNS);
+            clock(
+                "out.wav",
+                dir,
+                patterns,
+                &[&session],
+                start,
+                samples,
+                &profile(),
+                &mut index,
+            )
+        };
+
+        let half = plan(Some(NANOS - 500_000_000), Some(48000));
+        assert_eq!(half.start, Some((format!("{}.wav", STARTS[0]), 24000)));
+        assert_eq!(names(&half), ["1718186579000000000", "1718186580000000000"]);
+        assert!(half.notes.is_empty());
+        let long = plan(Some(NANOS), Some(96001));
+        assert_eq!(
+            long.notes,
+            ["warning: recording ends 1 samples before the requested end"]
+        );
+        let late = plan(Some(NANOS + 5_000_000_000), Some(100));
+        assert_eq!(
+            late.notes,
+            ["error: requested start not in audio data time range"]
+        );
+    }
+
+    #[test]
+    fn plans_cuts_timed_by_pps_markers() {
+        let dir = testing::dir();
+        testing::recordings(dir.path(), &STARTS, Some(NANOS));
+        let mut index = Index::open(dir.path());
+        let layout = PpsLayout::default();
+        let mut plan = |start, samples| {
+            let (dir, patterns) = (dir.path(), &discover::PATTERNS);
+            pps(
+                "out.wav",
+                dir,
+                patterns,
+                start,
+                samples,
+                &profile(),
+                &layout,
+                &mut index,
+            )
+        };
+
+        // Half a second before the marker at frame 100 of the middle recording
+        let half = plan(NANOS - 500_000_000, 72000);
+        assert_eq!(half.start, Some((format!("{}.wav", STARTS[0]), 24100)));
+        assert_eq!(names(&half).len(), 3);
+        assert!(half.notes.is_empty());
+        let long = plan(NANOS - 500_000_000, 120_000);
+        assert_eq!(
+            long.notes,
+            ["warning: recording ends 100 samples before the requested end"]
+        );
+    }
+
+    #[test]
+    fn reports_failures_in_the_plan_on_a_dry_run() {
+        let mut plans = Some(Vec::new());
+        fail(&mut plans, "out.wav", Class::Input, "no mode");
+        let plans = plans.unwrap();
+        assert_eq!(
+            (plans[0].output.as_str(), &plans[0].notes[..]),
+            ("out.wav", &["error: no mode".to_owned()][..])
+        );
+        assert_eq!(
+            skip("out.wav").notes,
+            ["skipped: already cut with the same parameters"]
+        );
+    }
+}
@@ -1,7 +1,9 @@
-use std::mem::transmute;
 use std::path::{Path, PathBuf};
 
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::discover::{self, NamePattern};
+use crate::index::Index;
+use crate::log;
+use crate::repair;
 
 #[derive(Debug)]
 pub struct Pps {
@@ -10,104 +12,183 @@ pub struct Pps {
     pub file: PathBuf,
 }
 
-fn get_pps(f: &PathBuf) -> Vec<Pps> {
-    let mut pps_vec = Vec::new();
-    let mut reader = match hound::WavReader::open(f) {
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum WordOrder {
+    /// Most significant word follows the marker
+    #[default]
+    HighFirst,
+    /// Least significant word follows the marker
+    LowFirst,
+}
+
+/// How PPS timestamps are embedded in the sample stream
+#[derive(clap::Args, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PpsLayout {
+    /// Sample value announcing a PPS timestamp
+    #[arg(long = "pps-marker", value_parser = parse_marker, default_value = "0xeeeeeeee")]
+    pub marker: u32,
+    /// Order of the timestamp words following the marker
+    #[arg(long = "pps-order", value_enum, default_value_t)]
+    pub order: WordOrder,
+    /// Timestamp width: 64 for nanoseconds in two words, 32 for whole seconds in one word
+    #[arg(long = "pps-bits", default_value_t = 64, value_parser = parse_bits)]
+    pub bits: u32,
+    /// Earliest plausible timestamp, RFC 3339 or nanos
+    #[arg(long = "pps-min", value_parser = crate::clock::parse_time, default_value = "2000-01-01T00:00:00Z")]
+    pub min_nanos: i64,
+    /// Latest plausible timestamp, RFC 3339 or nanos
+    #[arg(long = "pps-max", value_parser = crate::clock::parse_time, default_value = "2100-01-01T00:00:00Z")]
+    pub max_nanos: i64,
+}
+
+impl Default for PpsLayout {
+    fn default() -> Self {
+        Self {
+            marker: 0xeeee_eeee,
+            order: WordOrder::HighFirst,
+            bits: 64,
+            min_nanos: 946_684_800_000_000_000,
+            max_nanos: 4_102_444_800_000_000_000,
+        }
+    }
+}
+
+impl PpsLayout {
+    fn words(&self) -> usize {
+        self.bits as usize / 32
+    }
+
+    fn decode(&self, words: &[u32]) -> i64 {
+        match (self.words(), self.order) {
+            (1, _) => words[0] as i64 * 1_000_000_000,
+            (_, WordOrder::HighFirst) => ((words[0] as u64) << 32 | words[1] as u64) as i64,
+            (_, WordOrder::LowFirst) => ((words[1] as u64) << 32 | words[0] as u64) as i64,
+        }
+    }
+}
+
+fn parse_marker(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    }
+    .map_err(|e| format!("invalid marker '{s}': {e}"))
+}
+
+fn parse_bits(s: &str) -> Result<u32, String> {
+    match s {
+        "32" => Ok(32),
+        "64" => Ok(64),
+        _ => Err("must be 32 or 64".to_owned()),
+    }
+}
+
+/// Reads all PPS timestamps embedded in `f`; implausible or non-monotonic ones are
+/// reported and skipped.
+pub fn get_pps(f: &Path, layout: &PpsLayout) -> Vec<Pps> {
+    let mut pps_vec: Vec<Pps> = Vec::new();
+    let mut reader = match repair::open(f) {
         Ok(r) => r,
         Err(_e) => {
             return pps_vec;
         }
     };
-    let mut pps = false;
-    let mut first_read = false;
-    let mut prev = 0i32;
+    let mut marker_at = None;
+    let mut words = Vec::with_capacity(2);
+    let mut rejected = 0;
     for (i, s) in reader.samples::<i32>().enumerate() {
-        let sample = s.unwrap();
-        if sample == 0xeeee_eeee_u32 as i32 {
-            pps = true;
-        } else if pps {
-            if first_read {
-                pps = false;
-                first_read = false;
-                let nanos = unsafe { transmute::<[i32; 2], i64>([sample, prev]) };
-                pps_vec.push(Pps {
-                    nanos,
-                    sample: (i - 2) as u32,
-                    file: f.clone(),
-                });
-            } else {
-                first_read = true;
-                prev = sample;
-            }
+        let sample = s.unwrap() as u32;
+        if sample == layout.marker {
+            marker_at = Some(i);
+            words.clear();
+            continue;
+        }
+        let Some(at) = marker_at else {
+            continue;
+        };
+        words.push(sample);
+        if words.len() < layout.words() {
+            continue;
+        }
+        marker_at = None;
+
+        let nanos = layout.decode(&words);
+        let reason = if nanos < layout.min_nanos || nanos > layout.max_nanos {
+            Some("out of range")
+        } else if pps_vec.last().is_some_and(|p| p.nanos >= nanos) {
+            Some("not after the previous timestamp")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            log::debug(format!(
+                "Rejected PPS marker at sample {at} in {}: {nanos} is {reason}",
+                f.display()
+            ));
+            rejected += 1;
+            continue;
         }
+        pps_vec.push(Pps {
+            nanos,
+            sample: at as u32,
+            file: f.to_path_buf(),
+        });
+    }
+    if rejected > 0 {
+        log::warn(format!(
+            "{rejected} of {} PPS markers rejected in {}",
+            rejected + pps_vec.len(),
+            f.display()
+        ));
     }
     pps_vec
 }
 
-pub fn find_best(dir: &Path, from_nanos: i64) -> (Option<Pps>, i64, Vec<PathBuf>) {
-    let start_nanos = from_nanos - 1_000_000_000 * 60;
-    let mut waves = std::fs::read_dir(dir)
-        .unwrap()
-        .flat_map(|f| f.map(|e| e.path()))
-        .filter(|f| {
-            if let Some(ext) = f.extension() {
-                if !ext.eq("wav") {
-                    return false;
-                }
-            } else {
-                return false;
-            }
-            let str = f.file_name().unwrap().to_str().unwrap();
-            let str = &str[..str.len() - 4];
-            let nanos = str.parse::<i64>().unwrap();
-            nanos >= start_nanos
-        })
-        .collect::<Vec<_>>();
-    waves.sort_unstable();
-
-    let mut best_pps = None;
-    let mut best_diff = i64::MAX;
-
-    let n = waves.len() as u64;
-    let pb = ProgressBar::new(n);
-    let t = f64::from(n as u32).log10().ceil() as u64;
-    pb.set_style(
-        ProgressStyle::with_template(&format!(
-            "[{{elapsed_precise}}] {{bar:40.cyan/blue}} {{pos:>{t}}}/{{len:{t}}} ({{percent}}%)"
-        ))
-        .unwrap()
-        .progress_chars("##-"),
-    );
-
-    for (i, wav) in waves.iter().enumerate() {
-        let pps_vec = get_pps(wav);
-
-        let best = pps_vec
-            .into_iter()
-            .min_by_key(|x| (x.nanos - from_nanos).abs());
-
-        if let Some(best) = best {
-            let diff = (best.nanos - from_nanos).abs();
-            if diff < best_diff {
-                best_pps = Some(best);
-                best_diff = diff;
-            }
-        }
+/// Scans the recording whose file name says it covers `from_nanos`, widening to its
+/// neighbours until one yields PPS markers. Returns those markers and all waves in `dir`
+/// named by `patterns`.
+pub fn find_best(
+    dir: &Path,
+    patterns: &[NamePattern],
+    from_nanos: i64,
+    layout: &PpsLayout,
+    index: &mut Index,
+) -> (Vec<Pps>, Vec<PathBuf>) {
+    let waves = discover::recordings(dir, patterns);
 
-        pb.inc(1);
+    let n = waves.len();
+    let covering = waves
+        .partition_point(|r| r.nanos <= from_nanos)
+        .saturating_sub(1);
+    // covering, covering - 1, covering + 1, covering - 2, ...
+    let candidates = (0..n).flat_map(|d| [covering + d, covering.wrapping_sub(d + 1)]);
 
-        if i >= 5 && best_diff <= 500_000_000 {
-            break;
-        }
-        if i >= 15 && best_diff <= 60_000_000_000 {
+    let mut pps_vec = Vec::new();
+    for i in candidates.filter(|i| *i < n).take(n) {
+        pps_vec = index.pps(&waves[i].path, layout);
+        if !pps_vec.is_empty() {
             break;
         }
     }
 
-    pb.finish();
-    (best_pps, best_diff, waves)
+    (pps_vec, waves.into_iter().map(|r| r.path).collect())
 }
 
+/// Walks from the PPS at interleaved `sample` of `file` to `from_nanos`, returning the file
+/// and frame where it falls.
+#[allow(clippy::too_many_arguments)]
 pub fn find_start(
     from_nanos: i64,
     nanos: i64,
@@ -116,8 +197,9 @@ pub fn find_start(
     waves: &[PathBuf],
     channels: u32,
     freq: f64,
-) -> (PathBuf, u32) {
-    let sample = (sample - 1) / channels;
+    index: &mut Index,
+) -> Option<(PathBuf, u32)> {
+    let sample = sample / channels;
     let mut nanos_diff = from_nanos - nanos;
     let mut backward = false;
     let mut start_sample = 0u32;
@@ -135,13 +217,9 @@ pub fn find_start(
         } else {
             samples_diff -= sample;
             for wav in waves.iter().rev().skip_while(|x| *x != file).skip(1) {
-                let reader = match hound::WavReader::open(wav) {
-                    Ok(r) => r,
-                    Err(_e) => {
-                        continue;
-                    }
+                let Some((wav_dur, _)) = index.wav_info(wav) else {
+                    continue;
                 };
-                let wav_dur = reader.duration();
                 if samples_diff > wav_dur {
                     samples_diff -= wav_dur;
                 } else {
@@ -153,21 +231,16 @@ pub fn find_start(
             }
         }
     } else {
-        let reader = hound::WavReader::open(file.clone()).unwrap();
-        let wav_dur = reader.duration();
+        let (wav_dur, _) = index.wav_info(file)?;
         if sample + samples_diff <= wav_dur {
             start_sample = sample + samples_diff;
             start_found = true;
         } else {
             samples_diff -= wav_dur - sample;
             for wav in waves.iter().skip_while(|x| *x != file).skip(1) {
-                let reader = match hound::WavReader::open(wav) {
-                    Ok(r) => r,
-                    Err(_e) => {
-                        continue;
-                    }
+                let Some((wav_dur, _)) = index.wav_info(wav) else {
+                    continue;
                 };
-                let wav_dur = reader.duration();
                 if samples_diff > wav_dur {
                     samples_diff -= wav_dur;
                 } else {
@@ -180,7 +253,97 @@ pub fn find_start(
         }
     }
 
-    assert!(start_found, "Failed to find starting point");
+    start_found.then_some((start_file, start_sample))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const NANOS: i64 = 1_718_186_580_000_000_000;
+
+    fn words(nanos: i64) -> [u32; 2] {
+        [(nanos as u64 >> 32) as u32, nanos as u32]
+    }
+
+    #[test]
+    fn decodes_word_orders() {
+        let [high, low] = words(NANOS);
+        let layout = PpsLayout::default();
+        assert_eq!(layout.decode(&[high, low]), NANOS);
+        let layout = PpsLayout {
+            order: WordOrder::LowFirst,
+            ..Default::default()
+        };
+        assert_eq!(layout.decode(&[low, high]), NANOS);
+        let layout = PpsLayout {
+            bits: 32,
+            ..Default::default()
+        };
+        assert_eq!(layout.words(), 1);
+        assert_eq!(layout.decode(&[1_718_186_580]), NANOS);
+    }
 
-    (start_file, start_sample)
+    #[test]
+    fn parses_layout_flags() {
+        assert_eq!(parse_marker("0xEEEEEEEE"), Ok(0xeeee_eeee));
+        assert_eq!(parse_marker("4008636142"), Ok(0xeeee_eeee));
+        assert!(parse_marker("0xg").is_err());
+        assert_eq!(parse_bits("32"), Ok(32));
+        assert!(parse_bits("48").is_err());
+    }
+
+    #[test]
+    fn skips_implausible_markers() {
+        let dir = testing::dir();
+        let path = dir.path().join("1718186579700000000.wav");
+        let marker = PpsLayout::default().marker;
+        // Valid, out of range, going backwards, valid
+        let samples = [NANOS, 1, NANOS - 1, NANOS + 1_000_000_000]
+            .into_iter()
+            .flat_map(|nanos| [0, 0, marker].into_iter().chain(words(nanos)))
+            .map(|s| s as i32);
+        testing::wav(&path, 2, samples);
+
+        let pps = get_pps(&path, &PpsLayout::default());
+        let found = pps.iter().map(|p| (p.sample, p.nanos)).collect::<Vec<_>>();
+        assert_eq!(found, [(2, NANOS), (17, NANOS + 1_000_000_000)]);
+    }
+
+    #[test]
+    fn finds_the_start_from_markers_in_a_neighbouring_file() {
+        let dir = testing::dir();
+        // Only the middle recording has a marker
+        let starts = [NANOS - 1_000_000_000, NANOS, NANOS + 1_000_000_000];
+        testing::recordings(dir.path(), &starts, Some(NANOS));
+        let mut index = Index::open(dir.path());
+        let layout = PpsLayout::default();
+
+        let (markers, waves) = find_best(
+            dir.path(),
+            &discover::PATTERNS,
+            starts[0],
+            &layout,
+            &mut index,
+        );
+        assert_eq!(waves.len(), 3);
+        assert_eq!(markers.len(), 1);
+        assert_eq!((&markers[0].file, markers[0].sample), (&waves[1], 100));
+
+        let mut start = |from_nanos| {
+            find_start(
+                from_nanos, NANOS, 100, &waves[1], &waves, 1, 48000.0, &mut index,
+            )
+        };
+        // Within the file with the marker, then half a second either side of it
+        assert_eq!(start(NANOS + 1_000_000), Some((waves[1].clone(), 148)));
+        assert_eq!(start(NANOS - 500_000_000), Some((waves[0].clone(), 24100)));
+        assert_eq!(
+            start(NANOS + 1_500_000_000),
+            Some((waves[2].clone(), 24100))
+        );
+        // Past the recordings
+        assert_eq!(start(NANOS + 5_000_000_000), None);
+    }
 }
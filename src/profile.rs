@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+
+use crate::pps::PpsLayout;
+
+/// How recorded samples become output samples
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Decoder {
+    /// Every `step`th interleaved sample is written as recorded
+    #[default]
+    Channels,
+    /// Tagged I2S words are sorted into microphone rows and summed into beams
+    I2sBeams,
+}
+
+/// What a recorder writes: rates, channel layout, tag bits, PPS encoding and how to decode it.
+/// Built in for the `umc`, `i2s` and `rawi2s` modes; more can be given in a campaign config
+/// under `[profiles.<name>]`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceProfile {
+    /// Recorded frames per second
+    pub sample_rate: u32,
+    /// Output channels
+    #[serde(default = "one")]
+    pub channels: u16,
+    /// Take every `step`th sample of the interleaved input
+    #[serde(default = "one")]
+    pub step: usize,
+    /// Low bits of each sample tagging its microphone rather than carrying audio
+    #[serde(default)]
+    pub tag_bits: u32,
+    /// Layout of the PPS timestamps embedded in the audio, if the recorder writes them
+    #[serde(default)]
+    pub pps: Option<PpsLayout>,
+    #[serde(default)]
+    pub decoder: Decoder,
+}
+
+fn one<T: From<u8>>() -> T {
+    T::from(1)
+}
+
+/// I2S words decoded into one beam sample
+pub const I2S_DECIMATION: u32 = 4;
+
+impl DeviceProfile {
+    pub fn rate(&self) -> f64 {
+        self.sample_rate as f64
+    }
+
+    /// Output samples produced per recorded frame
+    pub fn samples_per_frame(&self) -> f64 {
+        match self.decoder {
+            Decoder::Channels => self.channels as f64,
+            Decoder::I2sBeams => 1.0 / I2S_DECIMATION as f64,
+        }
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.sample_rate == 0 || self.channels == 0 || self.step == 0 {
+            return Err("sample_rate, channels and step must be positive".to_owned());
+        }
+        if self.decoder == Decoder::I2sBeams && self.tag_bits != 4 {
+            return Err(
+                "the i2s-beams decoder needs 4 tag bits: a row bit and 3 microphone bits"
+                    .to_owned(),
+            );
+        }
+        if self.tag_bits >= 32 {
+            return Err("tag_bits must be below 32".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Spec of each output file
+    pub fn output_spec(&self) -> hound::WavSpec {
+        let (channels, sample_rate) = match self.decoder {
+            Decoder::Channels => (self.channels, self.sample_rate),
+            Decoder::I2sBeams => (1, self.sample_rate / I2S_DECIMATION),
+        };
+        hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+}
+
+/// Profiles by mode name
+pub struct Profiles(BTreeMap<String, DeviceProfile>);
+
+impl Default for Profiles {
+    fn default() -> Self {
+        let profiles = [
+            (
+                "umc",
+                DeviceProfile {
+                    sample_rate: 48000,
+                    channels: 1,
+                    step: 2,
+                    tag_bits: 0,
+                    pps: Some(PpsLayout::default()),
+                    decoder: Decoder::Channels,
+                },
+            ),
+            (
+                "i2s",
+                DeviceProfile {
+                    sample_rate: 192000,
+                    channels: 1,
+                    step: 1,
+                    tag_bits: 4,
+                    pps: None,
+                    decoder: Decoder::I2sBeams,
+                },
+            ),
+            (
+                "rawi2s",
+                DeviceProfile {
+                    sample_rate: 192000,
+                    channels: 4,
+                    step: 1,
+                    tag_bits: 0,
+                    pps: Some(PpsLayout::default()),
+                    decoder: Decoder::Channels,
+                },
+            ),
+        ];
+        Self(
+            profiles
+                .into_iter()
+                .map(|(name, p)| (name.to_owned(), p))
+                .collect(),
+        )
+    }
+}
+
+impl Profiles {
+    /// The built-in profiles, extended or overridden by `custom`
+    pub fn with(custom: &BTreeMap<String, DeviceProfile>) -> Self {
+        let mut profiles = Self::default();
+        profiles.0.extend(custom.clone());
+        profiles
+    }
+
+    pub fn get(&self, mode: &str) -> Result<&DeviceProfile, String> {
+        self.0.get(mode).ok_or_else(|| {
+            let names = self.0.keys().cloned().collect::<Vec<_>>();
+            format!(
+                "unknown mode '{mode}', expected one of {}",
+                names.join(", ")
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_built_in_modes() {
+        let profiles = Profiles::default();
+        let umc = profiles.get("umc").unwrap().output_spec();
+        assert_eq!((umc.channels, umc.sample_rate), (1, 48000));
+        let i2s = profiles.get("i2s").unwrap();
+        assert_eq!(i2s.output_spec().sample_rate, 48000);
+        assert_eq!(i2s.samples_per_frame(), 0.25);
+        let rawi2s = profiles.get("rawi2s").unwrap();
+        assert_eq!(rawi2s.output_spec().channels, 4);
+        assert_eq!(rawi2s.samples_per_frame(), 4.0);
+        assert!(["umc", "i2s", "rawi2s"].iter().all(|mode| profiles
+            .get(mode)
+            .unwrap()
+            .check()
+            .is_ok()));
+        let e = profiles.get("UMC").unwrap_err();
+        assert_eq!(e, "unknown mode 'UMC', expected one of i2s, rawi2s, umc");
+    }
+
+    #[test]
+    fn adds_and_overrides_profiles() {
+        let custom: BTreeMap<String, DeviceProfile> = toml::from_str(
+            "[umc]\nsample_rate = 96000\nstep = 2\n[tagged]\nsample_rate = 48000\ntag_bits = 32\n",
+        )
+        .unwrap();
+        let profiles = Profiles::with(&custom);
+        let umc = profiles.get("umc").unwrap();
+        assert_eq!(
+            (umc.sample_rate, umc.channels, umc.pps.as_ref()),
+            (96000, 1, None)
+        );
+        assert!(profiles.get("i2s").is_ok());
+        assert!(profiles.get("tagged").unwrap().check().is_err());
+        let beams = DeviceProfile {
+            tag_bits: 3,
+            decoder: Decoder::I2sBeams,
+            ..umc.clone()
+        };
+        assert!(beams.check().is_err());
+    }
+}
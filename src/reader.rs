@@ -0,0 +1,435 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use crate::clock::{self, Session};
+use crate::discover::{self, NamePattern};
+use crate::log::{self, Class};
+use crate::manifest::Provenance;
+use crate::repair;
+use crate::Record;
+
+type Samples = std::iter::StepBy<hound::WavIntoSamples<repair::Input, i32>>;
+
+/// What the stream passes on its way, in reading order
+pub enum Event<'a> {
+    Sample(i32),
+    /// Reading continues in `file` from frame `seek`
+    File {
+        file: PathBuf,
+        seek: u32,
+        channels: u16,
+    },
+    /// A stretch of recording timed by `records` starts
+    Session {
+        records: &'a [Record],
+    },
+    /// Nothing was recorded for `frames` frames before `session`
+    Gap {
+        frames: u64,
+        session: &'a Session,
+    },
+}
+
+/// Where the next stretch of recording is read from
+struct Stretch<'a> {
+    /// Frame to start at, counted from the first file and running on into the next ones
+    /// while it lies past their end
+    seek: Option<u32>,
+    end_file: Option<PathBuf>,
+    records: &'a [Record],
+}
+
+struct Open {
+    samples: Samples,
+    file: PathBuf,
+    seek: u32,
+    channels: u16,
+    /// Reader-wide frame of `seek`
+    base: f64,
+    /// Samples yielded from the file
+    read: u64,
+}
+
+/// The recordings of a directory as one continuous stream of samples: seeks to a time across
+/// sessions or to a frame of a file, keeps every `step`th interleaved sample, reports the
+/// files, sessions and gaps it passes and knows the UTC time of the sample it is at.
+pub struct TimedSampleReader<'a> {
+    waves: Vec<PathBuf>,
+    rate: f64,
+    step: usize,
+    /// Sessions still to be read after the current stretch, when seeking by time
+    sessions: &'a [&'a Session],
+    /// Time the next session is read from
+    nanos: i64,
+    stretch: Option<Stretch<'a>>,
+    /// Index into `waves` of the next file to open
+    next: usize,
+    open: Option<Open>,
+    events: VecDeque<Event<'a>>,
+    /// Input frames read before the open file
+    frames: f64,
+    /// UTC nanos of a reader-wide frame
+    anchor: Option<(i64, f64)>,
+    /// Session being read and the time it was entered at
+    session: Option<(&'a Session, i64)>,
+    uncertainty: Option<f64>,
+    /// Move on past files which cannot be read instead of ending the stream
+    skip_unreadable: bool,
+    provenance: Provenance,
+}
+
+impl<'a> TimedSampleReader<'a> {
+    /// A reader over the recordings under `input_dir` named by `patterns`, in time order,
+    /// recorded at `rate` frames per second
+    pub fn new(input_dir: &Path, patterns: &[NamePattern], rate: f64, step: usize) -> Self {
+        Self {
+            waves: discover::waves(input_dir, patterns),
+            rate,
+            step,
+            sessions: &[],
+            nanos: 0,
+            stretch: None,
+            next: 0,
+            open: None,
+            events: VecDeque::new(),
+            frames: 0.0,
+            anchor: None,
+            session: None,
+            uncertainty: None,
+            skip_unreadable: false,
+            provenance: Provenance::default(),
+        }
+    }
+
+    /// Reads from frame `seek` of `file` through `end_file`, or to the last file. Files are
+    /// matched by name, wherever they are under the input dir.
+    pub fn seek(&mut self, file: &Path, seek: u32, end_file: Option<&Path>, records: &'a [Record]) {
+        self.next = self
+            .waves
+            .iter()
+            .position(|w| w.file_name() == file.file_name())
+            .unwrap_or(self.waves.len());
+        self.stretch = Some(Stretch {
+            seek: Some(seek),
+            end_file: end_file.map(Path::to_path_buf),
+            records,
+        });
+        self.events.push_back(Event::Session { records });
+    }
+
+    /// Skips files which cannot be read, with a warning, and carries on with the next one.
+    /// Without this an unreadable file is an error which ends the stream, so that a cut is
+    /// never silently shortened.
+    pub fn skip_unreadable(&mut self) {
+        self.skip_unreadable = true;
+    }
+
+    /// Reads `sessions` from `nanos` on, one after the other, with a gap wherever a session
+    /// starts after the previous one ended
+    pub fn seek_time(&mut self, sessions: &'a [&'a Session], nanos: i64) {
+        self.sessions = sessions;
+        self.nanos = nanos;
+        self.uncertainty = Some(0.0);
+    }
+
+    /// UTC nanos of the next sample, extrapolated from the nearest clock record of its file
+    /// or from where the stream was sought to
+    pub fn time(&self) -> Option<i64> {
+        let (nanos, frame) = self.anchor?;
+        Some(nanos + ((self.position() - frame) / self.rate * 1e9).round() as i64)
+    }
+
+    /// Reader-wide frame of the next sample
+    fn position(&self) -> f64 {
+        self.open.as_ref().map_or(self.frames, |open| {
+            open.base + (open.read * self.step as u64) as f64 / open.channels as f64
+        })
+    }
+
+    pub fn next_event(&mut self) -> Option<Event<'a>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(event);
+            }
+            if let Some(open) = &mut self.open {
+                match open.samples.next() {
+                    Some(Ok(sample)) => {
+                        open.read += 1;
+                        return Some(Event::Sample(sample));
+                    }
+                    Some(Err(e)) => {
+                        let file = self.close();
+                        self.unreadable(&file, e);
+                    }
+                    None => {
+                        let file = self.close();
+                        self.passed(&file);
+                    }
+                }
+            } else if self.stretch.is_some() {
+                self.open_next();
+            } else {
+                self.leave_session();
+                if self.sessions.is_empty() {
+                    return None;
+                }
+                self.enter_session();
+            }
+        }
+    }
+
+    /// Opens the next file of the stretch, seeking into it if the stretch starts there
+    fn open_next(&mut self) {
+        let Some(stretch) = &mut self.stretch else {
+            return;
+        };
+        let Some(wav) = self.waves.get(self.next) else {
+            self.stretch = None;
+            return;
+        };
+        self.next += 1;
+        let mut reader = match repair::open(wav) {
+            Ok(reader) => reader,
+            Err(e) => {
+                let wav = wav.clone();
+                self.unreadable(&wav, e);
+                return;
+            }
+        };
+        let mut seek = 0;
+        if let Some(frame) = stretch.seek {
+            if frame > reader.duration() {
+                stretch.seek = Some(frame - reader.duration());
+                return;
+            }
+            reader.seek(frame).unwrap();
+            seek = frame;
+            stretch.seek = None;
+        }
+        let name = wav.file_name().unwrap().to_str().unwrap();
+        let nearest = stretch
+            .records
+            .iter()
+            .filter(|r| r.file == name)
+            .min_by_key(|r| r.file_sample.abs_diff(seek));
+        if let Some(r) = nearest {
+            let frame = self.frames + r.file_sample as f64 - seek as f64;
+            self.anchor = Some((r.time, frame));
+        }
+        let channels = reader.spec().channels;
+        self.open = Some(Open {
+            samples: reader.into_samples().step_by(self.step),
+            file: wav.clone(),
+            seek,
+            channels,
+            base: self.frames,
+            read: 0,
+        });
+        self.events.push_back(Event::File {
+            file: wav.clone(),
+            seek,
+            channels,
+        });
+    }
+
+    /// Ends the stretch if `file` was its last
+    fn passed(&mut self, file: &Path) {
+        if self
+            .stretch
+            .as_ref()
+            .and_then(|s| s.end_file.as_ref())
+            .is_some_and(|end| end.file_name() == file.file_name())
+        {
+            self.stretch = None;
+        }
+    }
+
+    /// Skips `file`, or ends the stream unless skipping unreadable files
+    fn unreadable(&mut self, file: &Path, e: hound::Error) {
+        if self.skip_unreadable {
+            log::warn(format!("Skipping unreadable file {}: {e}", file.display()));
+            self.passed(file);
+        } else {
+            log::error(
+                Class::Io,
+                format!("Error reading file {}: {e}", file.display()),
+            );
+            self.stretch = None;
+            self.sessions = &[];
+        }
+    }
+
+    /// Closes the open file, recording the frames read from it, and returns its path
+    fn close(&mut self) -> PathBuf {
+        let open = self.open.take().unwrap();
+        let frames = (open.read * self.step as u64).div_ceil(open.channels as u64);
+        self.provenance
+            .read(&open.file, open.seek as u64, open.seek as u64 + frames);
+        self.frames = open.base + (open.read * self.step as u64) as f64 / open.channels as f64;
+        open.file
+    }
+
+    /// Finds where the next session is read from `self.nanos` on
+    fn enter_session(&mut self) {
+        let (session, rest) = self.sessions.split_first().unwrap();
+        self.sessions = rest;
+        if !self
+            .waves
+            .iter()
+            .any(|w| w.file_stem().is_some_and(|s| s == session.name()))
+        {
+            log::warn(format!(
+                "Clock start not found for session {}",
+                session.name()
+            ));
+            return;
+        }
+        if self.nanos < session.start {
+            let frames = ((session.start - self.nanos) as f64 * self.rate / 1e9).round() as u64;
+            self.provenance.achieved_start.get_or_insert(self.nanos);
+            self.events.push_back(Event::Gap { frames, session });
+            self.nanos = session.start;
+        }
+        if self.nanos > session.end {
+            return;
+        }
+        let records = &session.records[..];
+        let Some(fix) = clock::locate(records, &session.rates, self.nanos, self.rate) else {
+            return;
+        };
+        let r = &records[fix.record];
+        let exact = r.file_sample as f64 + fix.sample - r.sample as f64;
+        let seek = exact.round().max(0.0);
+        let achieved = self.nanos + ((seek - exact) / self.rate * 1e9).round() as i64;
+        self.provenance.achieved_start.get_or_insert(achieved);
+        self.provenance
+            .clocks
+            .push(session.clock.display().to_string());
+        let Some(file) = discover::find(&self.waves, &r.file).cloned() else {
+            log::warn(format!(
+                "Recording {} of session {} not found",
+                r.file,
+                session.name()
+            ));
+            return;
+        };
+        let end_file = discover::find(&self.waves, &records[records.len() - 1].file).cloned();
+        self.seek(&file, seek as u32, end_file.as_deref(), records);
+        self.anchor = Some((achieved, self.frames));
+        self.session = Some((session, self.nanos));
+    }
+
+    /// Moves the time the next session is looked for to where the current one was left,
+    /// accounting for the timing uncertainty over it
+    fn leave_session(&mut self) {
+        let Some((session, from)) = self.session.take() else {
+            return;
+        };
+        let to = self.time().unwrap_or(from);
+        if let Some(uncertainty) = &mut self.uncertainty {
+            let records = &session.records;
+            let session = clock::uncertainty(records, &session.rates, from, to, self.rate);
+            *uncertainty = uncertainty.max(session);
+        }
+        self.nanos = to;
+    }
+
+    /// Stops reading, returning the input read and, when sought by time, the largest timing
+    /// uncertainty over the sessions read
+    pub fn finish(mut self) -> (Provenance, Option<f64>) {
+        if self.open.is_some() {
+            self.close();
+        }
+        self.leave_session();
+        (self.provenance, self.uncertainty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const NANOS: i64 = 1_718_186_580_000_000_000;
+
+    /// Three ten frame stereo recordings whose samples count up from 0, 100 and 200
+    fn recordings(dir: &Path) -> Vec<PathBuf> {
+        (0..3)
+            .map(|i| {
+                let path = dir.join(format!("{}.wav", NANOS + i as i64 * 1_000_000_000));
+                testing::wav(&path, 2, (0..20).map(|k| i * 100 + k));
+                path
+            })
+            .collect()
+    }
+
+    /// The samples up to the end of the stream, with a mark where each file starts
+    fn read(reader: &mut TimedSampleReader) -> Vec<String> {
+        let mut read = Vec::new();
+        while let Some(event) = reader.next_event() {
+            match event {
+                Event::Sample(sample) => read.push(sample.to_string()),
+                Event::File { file, seek, .. } => {
+                    let name = file.file_name().unwrap().to_str().unwrap();
+                    read.push(format!("{}@{seek}", &name[..10]));
+                }
+                Event::Session { .. } | Event::Gap { .. } => {}
+            }
+        }
+        read
+    }
+
+    #[test]
+    fn reads_across_files_from_a_frame() {
+        let dir = testing::dir();
+        let waves = recordings(dir.path());
+        let records = [Record {
+            time: NANOS + 1_000_000_000,
+            sample: 10,
+            file_sample: 0,
+            file: "1718186581000000000.wav".to_owned(),
+        }];
+        let mut reader = TimedSampleReader::new(dir.path(), &discover::PATTERNS, 48000.0, 2);
+        reader.seek(&waves[0], 4, Some(&waves[1]), &records);
+
+        // The left channel from frame 4 of the first file through the second one
+        let left = (8..20).step_by(2).chain((100..120).step_by(2));
+        let mut expected = left.map(|s| s.to_string()).collect::<Vec<_>>();
+        expected.insert(0, "1718186580@4".to_owned());
+        expected.insert(7, "1718186581@0".to_owned());
+        assert_eq!(read(&mut reader), expected);
+        // Ten frames past the record at the start of the second file
+        assert_eq!(reader.time(), Some(NANOS + 1_000_000_000 + 208_333));
+
+        let (provenance, uncertainty) = reader.finish();
+        let sources = provenance
+            .sources
+            .iter()
+            .map(|s| (s.start, s.end))
+            .collect::<Vec<_>>();
+        assert_eq!(sources, [(4, 10), (0, 10)]);
+        assert_eq!(uncertainty, None);
+    }
+
+    #[test]
+    fn ends_at_unreadable_files_unless_told_to_skip_them() {
+        let _serial = testing::serial();
+        let dir = testing::dir();
+        let waves = recordings(dir.path());
+        std::fs::write(&waves[1], b"RIFF").unwrap();
+        let errors = log::errors();
+
+        let mut reader = TimedSampleReader::new(dir.path(), &discover::PATTERNS, 48000.0, 1);
+        reader.seek(&waves[0], 9, None, &[]);
+        assert_eq!(read(&mut reader), ["1718186580@9", "18", "19"]);
+        assert_eq!(log::errors(), errors + 1);
+
+        let mut reader = TimedSampleReader::new(dir.path(), &discover::PATTERNS, 48000.0, 1);
+        reader.skip_unreadable();
+        reader.seek(&waves[0], 9, None, &[]);
+        let read = read(&mut reader);
+        assert_eq!(read[..4], ["1718186580@9", "18", "19", "1718186582@0"]);
+        assert_eq!(read.len(), 24);
+        assert_eq!(log::errors(), errors + 1);
+    }
+}
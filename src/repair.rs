@@ -0,0 +1,452 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::discover::{self, NamePattern};
+use crate::log::{self, Class};
+
+/// Where the data chunk of a wav file starts and how much of it the file really holds.
+/// Recorders that lose power leave the RIFF and data sizes at zero, or at whatever they
+/// wrote before the last samples.
+pub struct Repair {
+    /// Bytes before the data
+    pub data_offset: u64,
+    /// Data size in the header
+    pub declared: u32,
+    /// Data bytes in the file, in whole frames
+    pub recovered: u32,
+    /// Bytes after the recovered data which make up no whole frame
+    pub trailing: u64,
+    /// Bytes per frame
+    pub block_align: u16,
+    /// Where the chunk following the declared data starts. None when the data is the last
+    /// chunk, so any bytes after it are samples the header lost.
+    pub next_chunk: Option<u64>,
+    /// File size
+    pub len: u64,
+}
+
+impl Repair {
+    /// Whether the data size in the header is zero, not a whole number of frames, more
+    /// than the file holds, or less than it holds when the data is the last chunk
+    pub fn needed(&self) -> bool {
+        self.declared == 0
+            || !self.declared.is_multiple_of(self.block_align as u32)
+            || self.declared > self.recovered
+            || (self.declared < self.recovered && self.next_chunk.is_none())
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.recovered / self.block_align as u32
+    }
+
+    /// Pad byte needed after data of odd size when another chunk follows
+    fn pad(&self) -> u64 {
+        (self.next_chunk.is_some() && self.recovered % 2 == 1) as u64
+    }
+
+    /// Bytes of the chunks after the data
+    fn tail(&self) -> u64 {
+        self.next_chunk.map_or(0, |at| self.len - at)
+    }
+
+    /// The RIFF size for the recovered data and the chunks after it, `None` when it does
+    /// not fit in a u32
+    fn riff(&self) -> Option<u32> {
+        let size = self.data_offset - 8 + self.recovered as u64 + self.pad() + self.tail();
+        u32::try_from(size).ok()
+    }
+
+    /// Writes the recovered sizes into `header`, the file's bytes before the data
+    fn patch(&self, header: &mut [u8]) {
+        let at = self.data_offset as usize;
+        // `inspect` rejects files whose RIFF size would overflow
+        header[4..8].copy_from_slice(&self.riff().unwrap().to_le_bytes());
+        header[at - 4..at].copy_from_slice(&self.recovered.to_le_bytes());
+    }
+
+    pub fn describe(&self) -> String {
+        let mut s = format!(
+            "header says {} data bytes, file holds {} frames",
+            self.declared,
+            self.frames()
+        );
+        if self.trailing > 0 {
+            s += &format!(" and {} bytes of a partial frame", self.trailing);
+        }
+        s
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+/// Reads the chunk headers of `path` up to its data chunk and measures the data it holds
+pub fn inspect(path: &Path) -> Result<Repair, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let len = file.metadata().map_err(|e| e.to_string())?.len();
+    let mut riff = [0; 12];
+    file.read_exact(&mut riff)
+        .map_err(|_| "too short for a wav header")?;
+    if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        return Err("not a RIFF/WAVE file".to_owned());
+    }
+    let mut block_align = None;
+    // Chunks are skipped by their size alone, without pad bytes, the way hound reads them
+    loop {
+        let mut chunk = [0; 8];
+        file.read_exact(&mut chunk)
+            .map_err(|_| "no data chunk found")?;
+        let size = read_u32(&chunk[4..]);
+        match &chunk[..4] {
+            b"fmt " => {
+                let mut fmt = vec![0; size as usize];
+                file.read_exact(&mut fmt)
+                    .map_err(|_| "truncated fmt chunk")?;
+                if fmt.len() < 16 {
+                    return Err("invalid fmt chunk size".to_owned());
+                }
+                let channels = u16::from_le_bytes([fmt[2], fmt[3]]);
+                let align = u16::from_le_bytes([fmt[12], fmt[13]]);
+                let bits = u16::from_le_bytes([fmt[14], fmt[15]]);
+                if channels == 0 || align == 0 || align != channels * bits.div_ceil(8) {
+                    return Err(format!(
+                        "inconsistent fmt chunk: {channels} channels of {bits} bits in {align} byte frames"
+                    ));
+                }
+                block_align = Some(align);
+            }
+            b"data" => {
+                let block_align = block_align.ok_or("missing fmt chunk")?;
+                let data_offset = file.stream_position().map_err(|e| e.to_string())?;
+                let available = len - data_offset;
+                // The data size is a u32, so keep the whole frames that fit
+                let usable = available.min(u32::MAX as u64);
+                let mut recovered = (usable - usable % block_align as u64) as u32;
+                let mut trailing = available - recovered as u64;
+                let next_chunk = if size < recovered {
+                    chunk_follows(&mut file, size, len)?
+                } else {
+                    None
+                };
+                if next_chunk.is_some() {
+                    // Only the declared data is samples, cut to whole frames
+                    recovered = size - size % block_align as u32;
+                    trailing = (size - recovered) as u64;
+                }
+                let repair = Repair {
+                    data_offset,
+                    declared: size,
+                    recovered,
+                    trailing,
+                    block_align,
+                    next_chunk,
+                    len,
+                };
+                if repair.riff().is_none() {
+                    return Err("data too large for the RIFF size".to_owned());
+                }
+                return Ok(repair);
+            }
+            _ => {
+                file.seek(SeekFrom::Current(size as i64))
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+    }
+}
+
+/// Where a chunk header, four printable ASCII bytes and a size that fits in the file,
+/// follows the `size` bytes of data `file` is positioned at, if one does
+fn chunk_follows(file: &mut File, size: u32, len: u64) -> Result<Option<u64>, String> {
+    let at = file
+        .seek(SeekFrom::Current(size as i64))
+        .map_err(|e| e.to_string())?;
+    // A chunk of odd size may be followed by a pad byte
+    let pad = (size % 2 == 1).then_some(at + 1);
+    for at in std::iter::once(at).chain(pad) {
+        let mut chunk = [0; 8];
+        file.seek(SeekFrom::Start(at)).map_err(|e| e.to_string())?;
+        if file.read_exact(&mut chunk).is_err() {
+            continue;
+        }
+        if chunk[..4]
+            .iter()
+            .all(|b| b.is_ascii_graphic() || *b == b' ')
+            && at + 8 + read_u32(&chunk[4..]) as u64 <= len
+        {
+            return Ok(Some(at));
+        }
+    }
+    Ok(None)
+}
+
+/// Writes the recovered sizes into the header of `path` and cuts off a partial last frame,
+/// moving any chunks after the data up behind it
+pub fn rewrite(path: &Path, repair: &Repair) -> std::io::Result<()> {
+    let mut file = File::options().read(true).write(true).open(path)?;
+    let mut header = vec![0; repair.data_offset as usize];
+    file.read_exact(&mut header)?;
+    repair.patch(&mut header);
+    let end = repair.data_offset + repair.recovered as u64;
+    if let Some(at) = repair.next_chunk {
+        let mut tail = vec![0; repair.pad() as usize];
+        file.seek(SeekFrom::Start(at))?;
+        file.read_to_end(&mut tail)?;
+        file.seek(SeekFrom::Start(end))?;
+        file.write_all(&tail)?;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+    file.set_len(end + repair.pad() + repair.tail())?;
+    file.sync_all()
+}
+
+/// A wav file, read from disk or, when its header had to be repaired, from memory
+pub enum Input {
+    File(BufReader<File>),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Input::File(r) => r.read(buf),
+            Input::Memory(r) => r.read(buf),
+        }
+    }
+}
+
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Input::File(r) => r.seek(pos),
+            Input::Memory(r) => r.seek(pos),
+        }
+    }
+}
+
+/// Files already reported as repaired in memory
+static REPORTED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Opens `path` like `hound::WavReader::open`. When its header is broken, the whole file is
+/// read into memory and the header fixed there, so the recording can still be used.
+pub fn open(path: &Path) -> hound::Result<hound::WavReader<Input>> {
+    let repair = inspect(path).ok().filter(Repair::needed);
+    let Some(repair) = repair else {
+        return hound::WavReader::new(Input::File(BufReader::new(File::open(path)?)));
+    };
+    let mut bytes = std::fs::read(path)?;
+    let tail = repair
+        .next_chunk
+        .map(|at| bytes.split_off(at as usize))
+        .unwrap_or_default();
+    bytes.truncate((repair.data_offset + repair.recovered as u64) as usize);
+    bytes.resize(bytes.len() + repair.pad() as usize, 0);
+    bytes.extend(tail);
+    repair.patch(&mut bytes);
+    if REPORTED.lock().unwrap().insert(path.to_path_buf()) {
+        log::warn(format!(
+            "Repaired {} in memory, {}; run `wave repair --write` to fix the file",
+            path.display(),
+            repair.describe()
+        ));
+    }
+    hound::WavReader::new(Input::Memory(Cursor::new(bytes)))
+}
+
+/// Checks the wav files in `paths`, and the recordings under any dirs among them, for
+/// headers broken by a power loss, and with `write` fixes them in place. Recordings in dirs
+/// are those named by `patterns`.
+pub fn repair(paths: &[String], patterns: &[NamePattern], write: bool) {
+    let files = paths
+        .iter()
+        .map(Path::new)
+        .flat_map(|p| {
+            if p.is_dir() {
+                discover::waves(p, patterns)
+            } else {
+                vec![p.to_path_buf()]
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut broken = 0;
+    for file in &files {
+        let repair = match inspect(file) {
+            Ok(repair) => repair,
+            Err(e) => {
+                log::error(Class::Input, format!("{}: {e}", file.display()));
+                continue;
+            }
+        };
+        if !repair.needed() {
+            log::debug(format!(
+                "{}: ok, {} frames",
+                file.display(),
+                repair.frames()
+            ));
+            continue;
+        }
+        broken += 1;
+        if !write {
+            log::info(format!("{}: {}", file.display(), repair.describe()));
+            continue;
+        }
+        match rewrite(file, &repair) {
+            Ok(()) => log::info(format!(
+                "{}: {}; header rewritten",
+                file.display(),
+                repair.describe()
+            )),
+            Err(e) => log::error(
+                Class::Io,
+                format!("Rewriting header of {}: {e}", file.display()),
+            ),
+        }
+    }
+    log::info(format!(
+        "{broken} of {} files have a broken header{}",
+        files.len(),
+        if broken > 0 && !write {
+            "; rerun with --write to fix them"
+        } else {
+            ""
+        }
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// A 16 bit stereo wav of `frames` frames whose header declares `declared` data bytes,
+    /// followed by `extra`, in `dir`
+    fn wav(dir: &Path, name: &str, frames: u32, declared: u32, extra: &[u8]) -> PathBuf {
+        let mut bytes = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        bytes.extend(16u32.to_le_bytes());
+        for field in [1u16, 2] {
+            bytes.extend(field.to_le_bytes());
+        }
+        bytes.extend(48000u32.to_le_bytes());
+        bytes.extend((48000u32 * 4).to_le_bytes());
+        for field in [4u16, 16] {
+            bytes.extend(field.to_le_bytes());
+        }
+        bytes.extend(b"data");
+        bytes.extend(declared.to_le_bytes());
+        bytes.extend((0..frames * 2).flat_map(|i| (i as i16).to_le_bytes()));
+        bytes.extend(extra);
+        let riff = bytes.len() as u32 - 8;
+        bytes[4..8].copy_from_slice(&riff.to_le_bytes());
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn inspected(name: &str, frames: u32, declared: u32, extra: &[u8]) -> Repair {
+        let dir = testing::dir();
+        inspect(&wav(dir.path(), name, frames, declared, extra)).unwrap()
+    }
+
+    #[test]
+    fn accepts_intact_files() {
+        let repair = inspected("intact.wav", 100, 400, &[]);
+        assert!(!repair.needed());
+        assert_eq!((repair.data_offset, repair.frames()), (44, 100));
+        // A chunk after the data is not mistaken for samples
+        let list = [b"LIST".as_slice(), &4u32.to_le_bytes(), b"INFO"].concat();
+        let repair = inspected("list.wav", 100, 400, &list);
+        assert!(!repair.needed());
+        assert_eq!(repair.next_chunk, Some(44 + 400));
+    }
+
+    #[test]
+    fn finds_broken_sizes() {
+        // Never updated after the recorder lost power
+        let repair = inspected("zero.wav", 100, 0, &[]);
+        assert!(repair.needed());
+        assert_eq!(repair.frames(), 100);
+        // Declares more than was written
+        let repair = inspected("truncated.wav", 100, 4000, &[1, 2]);
+        assert!(repair.needed());
+        assert_eq!((repair.frames(), repair.trailing), (100, 2));
+        // Declares less than the last chunk holds
+        let repair = inspected("short.wav", 100, 200, &[]);
+        assert!(repair.needed());
+        assert_eq!(repair.next_chunk, None);
+        assert_eq!(repair.frames(), 100);
+        // Not a whole number of frames
+        assert!(inspected("partial.wav", 100, 398, &[]).needed());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let dir = testing::dir();
+        let path = dir.path().join("text.wav");
+        std::fs::write(&path, b"not a wav file at all").unwrap();
+        assert!(inspect(&path).is_err());
+        let repair = Repair {
+            data_offset: 44,
+            declared: 0,
+            recovered: u32::MAX - 3,
+            trailing: 0,
+            block_align: 4,
+            next_chunk: None,
+            len: u32::MAX as u64,
+        };
+        assert_eq!(repair.riff(), None);
+    }
+
+    #[test]
+    fn rewritten_files_read_whole() {
+        let dir = testing::dir();
+        let path = wav(dir.path(), "rewrite.wav", 100, 0, &[9]);
+        // As written, the header says the file holds nothing
+        assert_eq!(hound::WavReader::open(&path).unwrap().duration(), 0);
+        // Repaired in memory without touching the file
+        let samples = open(&path)
+            .unwrap()
+            .into_samples::<i16>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(samples, (0..200).collect::<Vec<i16>>());
+
+        rewrite(&path, &inspect(&path).unwrap()).unwrap();
+        assert!(!inspect(&path).unwrap().needed());
+        let reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.duration(), 100);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 44 + 400);
+    }
+
+    #[test]
+    fn keeps_chunks_after_misaligned_data() {
+        let dir = testing::dir();
+        // Half a frame more than 99 declared, then a LIST chunk
+        let list = [b"LIST".as_slice(), &4u32.to_le_bytes(), b"INFO"].concat();
+        let extra = [[7, 0].as_slice(), &list].concat();
+        let path = wav(dir.path(), "misaligned.wav", 99, 398, &extra);
+        let repair = inspect(&path).unwrap();
+        assert!(repair.needed());
+        assert_eq!(repair.next_chunk, Some(44 + 398));
+        assert_eq!((repair.frames(), repair.trailing), (99, 2));
+
+        let samples = |reader: hound::WavReader<_>| {
+            reader
+                .into_samples::<i16>()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        assert_eq!(samples(open(&path).unwrap()), (0..198).collect::<Vec<_>>());
+        rewrite(&path, &repair).unwrap();
+        let repair = inspect(&path).unwrap();
+        assert!(!repair.needed());
+        assert_eq!(repair.next_chunk, Some(44 + 396));
+        assert_eq!(std::fs::read(&path).unwrap()[44 + 396..], list);
+        assert_eq!(hound::WavReader::open(&path).unwrap().duration(), 99);
+        assert_eq!(samples(open(&path).unwrap()), (0..198).collect::<Vec<_>>());
+    }
+}
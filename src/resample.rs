@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::timing::PpsMark;
+
+/// Taps on each side of the interpolation point
+const HALF: usize = 16;
+/// Passband edge as a fraction of Nyquist, leaving room for the window's transition band
+const CUTOFF: f64 = 0.9;
+
+/// Blackman-windowed sinc evaluated `x` input frames away from the interpolation point
+fn kernel(x: f64) -> f64 {
+    let half = HALF as f64;
+    if x.abs() >= half {
+        return 0.0;
+    }
+    let sinc = if x == 0.0 {
+        1.0
+    } else {
+        (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+    };
+    let w = 0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos();
+    CUTOFF * sinc * w
+}
+
+/// Streaming band-limited resampler placing output frame `k` exactly at
+/// `start_nanos + k / rate` UTC, following the input clock through its PPS anchors.
+pub struct Resampler {
+    channels: usize,
+    rate: f64,
+    start_nanos: i64,
+    /// (input frame, UTC nanos) pairs, in order
+    anchors: Vec<(f64, i64)>,
+    /// Interleaved input, starting at frame `offset`
+    input: VecDeque<i32>,
+    offset: u64,
+    /// Next output frame
+    frame: u64,
+    /// Output samples still to produce
+    remaining: u64,
+}
+
+impl Resampler {
+    pub fn new(channels: u16, rate: u32, start_nanos: i64, samples: u64) -> Self {
+        Self {
+            channels: channels as usize,
+            rate: rate as f64,
+            start_nanos,
+            anchors: Vec::new(),
+            input: VecDeque::new(),
+            offset: 0,
+            frame: 0,
+            remaining: samples,
+        }
+    }
+
+    /// Registers the UTC time of an input frame
+    pub fn anchor(&mut self, mark: PpsMark) {
+        if self.anchors.last().is_none_or(|(_, n)| *n < mark.nanos) {
+            self.anchors.push((mark.sample as f64, mark.nanos));
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Fractional input frame at `nanos`, interpolated between anchors and extrapolated
+    /// from the outermost interval, or at the nominal rate while there are too few anchors
+    fn position(&self, nanos: f64) -> f64 {
+        match self.anchors.len() {
+            0 => (nanos - self.start_nanos as f64) * self.rate / 1e9,
+            1 => {
+                let (f, n) = self.anchors[0];
+                f + (nanos - n as f64) * self.rate / 1e9
+            }
+            len => {
+                let i = self
+                    .anchors
+                    .partition_point(|(_, n)| (*n as f64) <= nanos)
+                    .clamp(1, len - 1);
+                let ((fa, na), (fb, nb)) = (self.anchors[i - 1], self.anchors[i]);
+                fa + (nanos - na as f64) * (fb - fa) / (nb - na) as f64
+            }
+        }
+    }
+
+    /// Feeds one interleaved input sample, writing any output samples that became ready
+    pub fn push(&mut self, sample: i32, out: &mut impl FnMut(i32)) {
+        self.input.push_back(sample);
+        if self.input.len().is_multiple_of(self.channels) {
+            self.drain(false, out);
+        }
+    }
+
+    /// Writes the output the buffered input still covers, extrapolating past the last anchor
+    pub fn flush(&mut self, out: &mut impl FnMut(i32)) {
+        self.drain(true, out);
+    }
+
+    fn drain(&mut self, flush: bool, out: &mut impl FnMut(i32)) {
+        let available = self.offset + (self.input.len() / self.channels) as u64;
+        while self.remaining > 0 {
+            let nanos = self.start_nanos as f64 + self.frame as f64 * 1e9 / self.rate;
+            if !flush && (self.anchors.len() < 2 || (self.anchors.last().unwrap().1 as f64) < nanos)
+            {
+                break;
+            }
+            let pos = self.position(nanos);
+            let center = pos.floor() as i64;
+            if center >= available as i64 || !flush && center + HALF as i64 >= available as i64 {
+                break;
+            }
+
+            for c in 0..self.channels {
+                let mut acc = 0.0;
+                for j in center - HALF as i64 + 1..=center + HALF as i64 {
+                    if j < self.offset as i64 || j >= available as i64 {
+                        continue;
+                    }
+                    let idx = (j as u64 - self.offset) as usize * self.channels + c;
+                    acc += self.input[idx] as f64 * kernel(pos - j as f64);
+                }
+                out(acc.round().clamp(i32::MIN as f64, i32::MAX as f64) as i32);
+            }
+            self.frame += 1;
+            self.remaining = self.remaining.saturating_sub(self.channels as u64);
+
+            // Frames left of the window will not be needed again
+            let keep_from = (center - HALF as i64 + 1).max(0) as u64;
+            while self.offset < keep_from.min(available) {
+                self.input.drain(..self.channels);
+                self.offset += 1;
+            }
+        }
+    }
+
+    /// Output frame of each PPS, which sits exactly where its time falls on the UTC grid
+    pub fn marks(&self) -> Vec<PpsMark> {
+        self.anchors
+            .iter()
+            .map(|(_, nanos)| ((nanos - self.start_nanos) as f64 * self.rate / 1e9, *nanos))
+            .filter(|(frame, _)| *frame >= 0.0 && (*frame as u64) < self.frame)
+            .map(|(frame, nanos)| PpsMark {
+                sample: frame.round() as u64,
+                nanos,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: i64 = 1_718_186_580_000_000_000;
+
+    #[test]
+    fn moves_a_fast_clock_onto_the_utc_grid() {
+        // The recorder's clock runs 10 Hz fast, so it takes 48010 frames per UTC second
+        let input_rate = 48010.0;
+        let tone = |seconds: f64| 1e6 * (2.0 * PI * 1000.0 * seconds).sin();
+        let mut resampler = Resampler::new(1, 48000, START, 96000);
+        for second in 0..=3 {
+            resampler.anchor(PpsMark {
+                sample: second * 48010,
+                nanos: START + second as i64 * 1_000_000_000,
+            });
+        }
+
+        let mut output = Vec::new();
+        let mut out = |s| output.push(s);
+        for frame in 0..3 * 48010 {
+            resampler.push(tone(frame as f64 / input_rate).round() as i32, &mut out);
+        }
+        resampler.flush(&mut out);
+
+        assert!(resampler.done());
+        assert_eq!(output.len(), 96000);
+        // Away from the start, where the filter has no input to its left, every sample is
+        // the tone at its exact UTC time
+        for (k, sample) in output.iter().enumerate().skip(HALF) {
+            let expected = tone(k as f64 / 48000.0);
+            assert!(
+                (*sample as f64 - expected).abs() < 1e4,
+                "sample {k}: {sample} instead of {expected}"
+            );
+        }
+        let marks = resampler.marks();
+        let frames = marks.iter().map(|m| m.sample).collect::<Vec<_>>();
+        assert_eq!(frames, [0, 48000]);
+    }
+}
@@ -0,0 +1,54 @@
+//! Fixtures shared by the unit tests
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// An empty directory, removed with everything in it when dropped, also when a test fails
+pub fn dir() -> tempfile::TempDir {
+    tempfile::Builder::new().prefix("wave-").tempdir().unwrap()
+}
+
+/// Held by tests which log errors or write outputs, since an output is discarded when any
+/// error is logged while it is open
+pub fn serial() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A PPS marker for `nanos` as the default layout embeds it in the audio
+pub fn marker(nanos: i64) -> [i32; 3] {
+    let marker = crate::pps::PpsLayout::default().marker;
+    [marker as i32, (nanos >> 32) as i32, nanos as i32]
+}
+
+/// One second mono recordings in `dir` named by their `starts` nanos. The one starting at
+/// `marked` has a PPS marker for that time at frame 100.
+pub fn recordings(dir: &Path, starts: &[i64], marked: Option<i64>) -> Vec<PathBuf> {
+    starts
+        .iter()
+        .map(|&start| {
+            let path = dir.join(format!("{start}.wav"));
+            let mut samples = vec![0; 48000];
+            if marked == Some(start) {
+                samples[100..103].copy_from_slice(&marker(start));
+            }
+            wav(&path, 1, samples);
+            path
+        })
+        .collect()
+}
+
+/// Writes interleaved `samples` as a 32 bit wav at 48 kHz
+pub fn wav(path: &Path, channels: u16, samples: impl IntoIterator<Item = i32>) {
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate: 48000,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec).unwrap();
+    for sample in samples {
+        writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+}
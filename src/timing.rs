@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use crate::log;
+use crate::Record;
+
+/// A clock record mapped onto an output sample index
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct PpsMark {
+    pub sample: u64,
+    pub nanos: i64,
+}
+
+/// Absolute time information about a written output
+#[derive(Debug, Default)]
+pub struct Timing {
+    pub start_nanos: Option<i64>,
+    pub pps: Vec<PpsMark>,
+    /// Estimated 1-sigma timing error in nanos, where the clock model provides one
+    pub uncertainty: Option<f64>,
+}
+
+/// Collects the clock records that fall inside a cut while its input files are being read.
+pub struct PpsTracker<'a> {
+    by_file: HashMap<&'a str, Vec<&'a Record>>,
+    /// Output frames produced per input frame
+    ratio: f64,
+    pps: Vec<PpsMark>,
+}
+
+impl<'a> PpsTracker<'a> {
+    pub fn new(records: &'a [Record], ratio: f64) -> Self {
+        let mut by_file: HashMap<&str, Vec<&Record>> = HashMap::new();
+        for r in records {
+            by_file.entry(r.file.as_str()).or_default().push(r);
+        }
+        Self {
+            by_file,
+            ratio,
+            pps: Vec::new(),
+        }
+    }
+
+    /// Registers an input file which is read from frame `seek` onwards, with its first
+    /// frame landing on output frame `out_frame` (negative while output is still delayed).
+    pub fn file(&mut self, file_name: &str, seek: u32, out_frame: i64) {
+        let Some(records) = self.by_file.get(file_name) else {
+            return;
+        };
+        for r in records.iter().filter(|r| r.file_sample >= seek) {
+            let sample = out_frame + ((r.file_sample - seek) as f64 * self.ratio).round() as i64;
+            if sample >= 0 {
+                self.pps.push(PpsMark {
+                    sample: sample as u64,
+                    nanos: r.time,
+                });
+            }
+        }
+    }
+
+    /// Marks collected so far
+    pub fn marks(&self) -> &[PpsMark] {
+        &self.pps
+    }
+
+    /// Returns the marks that landed before `frames`, the number of frames actually written.
+    pub fn finish(mut self, frames: u64) -> Vec<PpsMark> {
+        self.pps.retain(|p| p.sample < frames);
+        self.pps
+    }
+}
+
+/// Progress message suffix reporting `uncertainty`, warning when it exceeds `max` nanos
+pub fn uncertainty_message(uncertainty: Option<f64>, max: Option<i64>) -> String {
+    let Some(uncertainty) = uncertainty else {
+        return String::new();
+    };
+    if max.is_some_and(|max| uncertainty > max as f64) {
+        log::warn(format!(
+            "estimated timing uncertainty {:.1} us exceeds the limit",
+            uncertainty / 1e3
+        ));
+    }
+    format!(", timing uncertainty ±{:.1} us", uncertainty / 1e3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NANOS: i64 = 1_718_186_580_000_000_000;
+
+    fn record(second: i64, file: &str, file_sample: u32) -> Record {
+        Record {
+            time: NANOS + second * 1_000_000_000,
+            sample: 0,
+            file_sample,
+            file: file.to_owned(),
+        }
+    }
+
+    #[test]
+    fn maps_records_onto_output_frames() {
+        let records = [
+            record(0, "a.wav", 1000),
+            record(1, "a.wav", 193_000),
+            record(2, "b.wav", 1000),
+            record(3, "b.wav", 193_000),
+        ];
+        // Four input frames per output frame
+        let mut tracker = PpsTracker::new(&records, 0.25);
+        // Read from past the first record of a.wav
+        tracker.file("a.wav", 2000, 0);
+        tracker.file("c.wav", 0, 48000);
+        tracker.file("b.wav", 0, 48000);
+        let marks = tracker
+            .marks()
+            .iter()
+            .map(|m| (m.sample, m.nanos))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            marks,
+            [
+                (47750, NANOS + 1_000_000_000),
+                (48250, NANOS + 2_000_000_000),
+                (96250, NANOS + 3_000_000_000)
+            ]
+        );
+        // Marks past the end of what was written are dropped
+        assert_eq!(tracker.finish(96250).len(), 2);
+
+        // Records before the output starts are dropped too
+        let mut tracker = PpsTracker::new(&records, 1.0);
+        tracker.file("a.wav", 0, -100_000);
+        let marks = tracker.finish(u64::MAX);
+        assert_eq!((marks.len(), marks[0].sample), (1, 93_000));
+    }
+}
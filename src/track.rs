@@ -0,0 +1,381 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat};
+
+use crate::log::{self, Class};
+use crate::CutRecord;
+
+const WGS84_A: f64 = 6_378_137.0;
+const WGS84_E2: f64 = 6.694_379_990_14e-3;
+
+/// A position of the drone
+#[derive(Clone, Copy, Debug)]
+pub struct TrackPoint {
+    pub nanos: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+}
+
+/// Surveyed position of a module; `heading` is the bearing of its front in degrees from north
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ModulePosition {
+    pub module: u8,
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+    #[serde(default)]
+    pub heading: f64,
+}
+
+#[derive(serde::Deserialize)]
+struct CsvPoint {
+    time: String,
+    lat: f64,
+    lon: f64,
+    #[serde(alias = "ele", alias = "altitude")]
+    alt: f64,
+}
+
+/// Reads a GPX file, or a csv with columns time (RFC 3339 or nanos), lat, lon, alt
+pub fn read_track(path: &Path) -> Result<Vec<TrackPoint>, String> {
+    let mut points = if path.extension().is_some_and(|e| e == "gpx") {
+        read_gpx(path)?
+    } else {
+        let mut reader = csv::Reader::from_path(path).map_err(|e| e.to_string())?;
+        reader
+            .deserialize::<CsvPoint>()
+            .map(|p| {
+                let p = p.map_err(|e| e.to_string())?;
+                Ok(TrackPoint {
+                    nanos: crate::clock::parse_time(&p.time)?,
+                    lat: p.lat,
+                    lon: p.lon,
+                    alt: p.alt,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?
+    };
+    points.sort_by_key(|p| p.nanos);
+    Ok(points)
+}
+
+fn read_gpx(path: &Path) -> Result<Vec<TrackPoint>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let doc = roxmltree::Document::parse(&text).map_err(|e| e.to_string())?;
+    let mut points = Vec::new();
+    for pt in doc.descendants().filter(|n| n.has_tag_name("trkpt")) {
+        let attr = |name| -> Result<f64, String> {
+            pt.attribute(name)
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| format!("trkpt without {name}"))
+        };
+        let child = |name| {
+            pt.children()
+                .find(|c| c.has_tag_name(name))
+                .and_then(|c| c.text())
+        };
+        let Some(time) = child("time") else {
+            continue;
+        };
+        points.push(TrackPoint {
+            nanos: crate::clock::parse_time(time.trim())?,
+            lat: attr("lat")?,
+            lon: attr("lon")?,
+            alt: child("ele")
+                .and_then(|e| e.trim().parse().ok())
+                .unwrap_or(0.0),
+        });
+    }
+    Ok(points)
+}
+
+pub fn read_modules(path: &Path) -> Result<Vec<ModulePosition>, String> {
+    let mut reader = csv::Reader::from_path(path).map_err(|e| e.to_string())?;
+    reader
+        .deserialize()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())
+}
+
+fn ecef(lat: f64, lon: f64, alt: f64) -> [f64; 3] {
+    let (lat, lon) = (lat.to_radians(), lon.to_radians());
+    let n = WGS84_A / (1.0 - WGS84_E2 * lat.sin().powi(2)).sqrt();
+    [
+        (n + alt) * lat.cos() * lon.cos(),
+        (n + alt) * lat.cos() * lon.sin(),
+        (n * (1.0 - WGS84_E2) + alt) * lat.sin(),
+    ]
+}
+
+impl ModulePosition {
+    /// East, north, up offset of `p` from the module in metres
+    pub fn enu(&self, p: &TrackPoint) -> [f64; 3] {
+        let o = ecef(self.lat, self.lon, self.alt);
+        let q = ecef(p.lat, p.lon, p.alt);
+        let d = [q[0] - o[0], q[1] - o[1], q[2] - o[2]];
+        let (lat, lon) = (self.lat.to_radians(), self.lon.to_radians());
+        [
+            -lon.sin() * d[0] + lon.cos() * d[1],
+            -lat.sin() * lon.cos() * d[0] - lat.sin() * lon.sin() * d[1] + lat.cos() * d[2],
+            lat.cos() * lon.cos() * d[0] + lat.cos() * lon.sin() * d[1] + lat.sin() * d[2],
+        ]
+    }
+
+    pub fn distance(&self, p: &TrackPoint) -> f64 {
+        let [e, n, u] = self.enu(p);
+        (e * e + n * n + u * u).sqrt()
+    }
+
+    /// Azimuth clockwise from the module's heading and elevation above its horizon, in degrees
+    pub fn direction(&self, p: &TrackPoint) -> (f64, f64) {
+        let [e, n, u] = self.enu(p);
+        let azimuth = (e.atan2(n).to_degrees() - self.heading).rem_euclid(360.0);
+        (azimuth, u.atan2(e.hypot(n)).to_degrees())
+    }
+}
+
+/// Position at `nanos`, interpolated linearly between track points
+pub fn position_at(track: &[TrackPoint], nanos: i64) -> Option<TrackPoint> {
+    let i = track.partition_point(|p| p.nanos <= nanos);
+    if i == 0 || i == track.len() && track[i - 1].nanos != nanos {
+        return None;
+    }
+    let a = track[i - 1];
+    let Some(b) = track.get(i) else {
+        return Some(a);
+    };
+    let t = (nanos - a.nanos) as f64 / (b.nanos - a.nanos) as f64;
+    Some(TrackPoint {
+        nanos,
+        lat: a.lat + t * (b.lat - a.lat),
+        lon: a.lon + t * (b.lon - a.lon),
+        alt: a.alt + t * (b.alt - a.alt),
+    })
+}
+
+#[derive(serde::Serialize)]
+struct Geometry {
+    time: i64,
+    /// Seconds from the start of the cut
+    offset: f64,
+    distance: f64,
+    azimuth: f64,
+    elevation: f64,
+    /// Rate of change of distance in m/s, positive when receding
+    radial_velocity: f64,
+}
+
+/// Most geometry rows per second, well above how fast a track changes
+const MAX_RATE: f64 = 1000.0;
+
+/// Parses a geometry sidecar rate in rows per second, which must be positive and at most
+/// `MAX_RATE`
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate = s
+        .parse::<f64>()
+        .map_err(|_| format!("invalid rate '{s}'"))?;
+    if !(rate > 0.0 && rate <= MAX_RATE) {
+        return Err(format!(
+            "rate must be above 0 and at most {MAX_RATE} rows per second"
+        ));
+    }
+    Ok(rate)
+}
+
+/// Where the drone was relative to one module, for writing alongside its cuts
+pub struct GroundTruth {
+    pub track: Vec<TrackPoint>,
+    pub position: ModulePosition,
+    /// Rows per second
+    pub rate: f64,
+}
+
+fn sidecar(output: &str) -> PathBuf {
+    Path::new(output).with_extension("geometry.csv")
+}
+
+impl GroundTruth {
+    /// Writes `<output>.geometry.csv` covering `start` to `end` where the track has data
+    pub fn write(&self, output: &str, start: i64, end: i64) {
+        let path = sidecar(output);
+        if let Err(e) = self.write_rows(&path, start, end) {
+            log::error(Class::Io, format!("Writing {}: {e}", path.display()));
+        }
+    }
+
+    /// Whether `output` has no geometry sidecar yet
+    pub fn missing(output: &str) -> bool {
+        !sidecar(output).exists()
+    }
+
+    fn write_rows(&self, path: &Path, start: i64, end: i64) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_path(path)?;
+        let step = (1e9 / self.rate).round() as i64;
+        // Central difference over one row for the radial velocity
+        let half = step / 2;
+        for time in (start..=end).step_by(step as usize) {
+            let Some(p) = position_at(&self.track, time) else {
+                continue;
+            };
+            let (azimuth, elevation) = self.position.direction(&p);
+            let (before, after) = (
+                position_at(&self.track, time - half).unwrap_or(p),
+                position_at(&self.track, time + half).unwrap_or(p),
+            );
+            let dt = (after.nanos - before.nanos) as f64 / 1e9;
+            let radial_velocity = if dt > 0.0 {
+                (self.position.distance(&after) - self.position.distance(&before)) / dt
+            } else {
+                0.0
+            };
+            writer.serialize(Geometry {
+                time,
+                offset: (time - start) as f64 / 1e9,
+                distance: self.position.distance(&p),
+                azimuth,
+                elevation,
+                radial_velocity,
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Cuts for the stretches of `track` where the drone stays within one of the distance
+/// bands between consecutive `edges` of a module, lasting at least `min_nanos`
+pub fn cuts(
+    track: &[TrackPoint],
+    modules: &[ModulePosition],
+    edges: &[f64],
+    flight: &str,
+    min_nanos: i64,
+) -> Vec<CutRecord> {
+    let band = |d: f64| edges.windows(2).position(|w| w[0] <= d && d < w[1]);
+    let time =
+        |nanos| DateTime::from_timestamp_nanos(nanos).to_rfc3339_opts(SecondsFormat::AutoSi, true);
+
+    let mut cuts = Vec::new();
+    for m in modules {
+        // (band, first, last) of the stretch being followed
+        let mut current: Option<(usize, i64, i64)> = None;
+        let mut push = |current: Option<(usize, i64, i64)>| {
+            if let Some((b, start, end)) = current {
+                if end - start >= min_nanos {
+                    cuts.push((
+                        start,
+                        CutRecord {
+                            start: time(start),
+                            end: time(end),
+                            range: format!("{}-{}m", edges[b], edges[b + 1]),
+                            flight: flight.to_owned(),
+                            modules: Some(m.module.to_string()),
+                            ..Default::default()
+                        },
+                    ));
+                }
+            }
+        };
+        for p in track {
+            let b = band(m.distance(p));
+            match (current, b) {
+                (Some((cb, start, _)), Some(b)) if cb == b => current = Some((b, start, p.nanos)),
+                (_, b) => {
+                    push(current);
+                    current = b.map(|b| (b, p.nanos, p.nanos));
+                }
+            }
+        }
+        push(current);
+    }
+    cuts.sort_by_key(|(start, _)| *start);
+    cuts.into_iter().map(|(_, cut)| cut).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const NANOS: i64 = 1_718_186_580_000_000_000;
+
+    fn module() -> ModulePosition {
+        ModulePosition {
+            module: 1,
+            lat: 52.0,
+            lon: 4.0,
+            alt: 0.0,
+            heading: 0.0,
+        }
+    }
+
+    /// A point `metres` north of the module, `second` seconds into the track
+    fn north(second: i64, metres: f64) -> TrackPoint {
+        TrackPoint {
+            nanos: NANOS + second * 1_000_000_000,
+            lat: 52.0 + metres / 111_000.0,
+            lon: 4.0,
+            alt: 0.0,
+        }
+    }
+
+    #[test]
+    fn cuts_the_stretches_within_each_band() {
+        // Flying away at 10 m/s, from 5 m to 295 m
+        let track = (0..30)
+            .map(|t| north(t, 5.0 + 10.0 * t as f64))
+            .collect::<Vec<_>>();
+        let edges = [0.0, 100.0, 200.0];
+        let found = cuts(&track, &[module()], &edges, "3", 5_000_000_000);
+        let rows = found
+            .iter()
+            .map(|c| (c.start.as_str(), c.end.as_str(), c.range.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                ("2024-06-12T10:03:00Z", "2024-06-12T10:03:09Z", "0-100m"),
+                ("2024-06-12T10:03:10Z", "2024-06-12T10:03:19Z", "100-200m")
+            ]
+        );
+        assert_eq!(
+            (found[0].flight.as_str(), found[0].modules.as_deref()),
+            ("3", Some("1"))
+        );
+        // Stretches shorter than the minimum are left out
+        assert!(cuts(&track, &[module()], &edges, "3", 10_000_000_000).is_empty());
+    }
+
+    #[test]
+    fn reads_gpx_and_csv_tracks() {
+        let dir = testing::dir();
+        let gpx = dir.path().join("track.gpx");
+        std::fs::write(
+            &gpx,
+            r#"<gpx><trk><trkseg>
+                <trkpt lat="52.001" lon="4.0"><ele>120</ele><time>2024-06-12T10:03:01Z</time></trkpt>
+                <trkpt lat="52.0" lon="4.0"><ele>100</ele><time>2024-06-12T10:03:00Z</time></trkpt>
+                <trkpt lat="52.002" lon="4.0"/>
+            </trkseg></trk></gpx>"#,
+        )
+        .unwrap();
+        let csv = dir.path().join("track.csv");
+        std::fs::write(
+            &csv,
+            "time,lat,lon,ele\n2024-06-12T10:03:00Z,52.0,4.0,100\n1718186581000000000,52.001,4.0,120\n",
+        )
+        .unwrap();
+        for path in [gpx, csv] {
+            let track = read_track(&path).unwrap();
+            let points = track
+                .iter()
+                .map(|p| (p.nanos, p.lat, p.alt))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                points,
+                [(NANOS, 52.0, 100.0), (NANOS + 1_000_000_000, 52.001, 120.0)]
+            );
+        }
+    }
+}
@@ -1,10 +1,21 @@
+use std::io;
 use std::path::Path;
 
 use indicatif::{ProgressBar, ProgressStyle};
 
+use crate::clock::Session;
+use crate::discover::NamePattern;
+use crate::index::Index;
+use crate::log::{self, Class};
+use crate::manifest::{Gap, Provenance};
+use crate::output::{Options, Writer};
+use crate::pps::{self, PpsLayout};
+use crate::profile::DeviceProfile;
+use crate::reader::{Event, TimedSampleReader};
+use crate::resample::Resampler;
+use crate::timing::{uncertainty_message, PpsMark, PpsTracker, Timing};
 use crate::Record;
 
-const FREQ: f64 = 48000.0;
 //const CHANNELS: u32 = 2;
 
 //fn wav_file_to_nanos(f: &Path) -> i64 {
@@ -13,158 +24,306 @@ const FREQ: f64 = 48000.0;
 //    str.parse::<i64>().unwrap()
 //}
 
+/// Cuts from the sessions covering the requested window, in order, filling the time
+/// between consecutive sessions with silence.
 #[allow(clippy::too_many_arguments)]
 pub fn make_wav<P: std::convert::AsRef<Path>>(
     output: P,
     input_dir: P,
-    clock: P,
+    patterns: &[NamePattern],
+    sessions: &[&Session],
     start: Option<i64>,
     samples: Option<u64>,
-    step: Option<usize>,
-    channels: Option<u16>,
-    sample_rate: Option<u32>,
+    profile: &DeviceProfile,
+    options: &Options,
 ) {
-    let mut waves = std::fs::read_dir(input_dir.as_ref())
-        .unwrap()
-        .flat_map(|f| f.map(|e| e.path()))
-        .collect::<Vec<_>>();
-    waves.sort_unstable();
+    let spec = profile.output_spec();
 
-    let spec = hound::WavSpec {
-        channels: channels.unwrap_or(1),
-        sample_rate: sample_rate.unwrap_or(48000),
-        bits_per_sample: 32,
-        sample_format: hound::SampleFormat::Int,
+    let Some(first) = sessions.first() else {
+        log::error(
+            Class::OutOfRange,
+            "Requested start not in audio data time range",
+        );
+        return;
+    };
+    let start_nanos = start.unwrap_or(first.start);
+    let samples = if let Some(samples) = samples {
+        samples
+    } else {
+        first.records[first.records.len() - 1].sample
     };
 
-    let clock_start_nanos_str = clock.as_ref().file_stem().unwrap().to_str().unwrap();
-
-    let mut wav_iter = waves.iter().peekable();
-    while let Some(wav) = wav_iter.peek() {
-        if wav.file_stem().unwrap().to_str().unwrap() == clock_start_nanos_str {
-            break;
-        }
-        wav_iter.next();
-    }
-
-    if wav_iter.peek().is_none() {
-        eprintln!("Clock start not found");
+    let mut reader =
+        TimedSampleReader::new(input_dir.as_ref(), patterns, profile.rate(), profile.step);
+    reader.seek_time(sessions, start_nanos);
+    let Ok(mut cut) = Cut::new(output.as_ref(), spec, samples, start_nanos, options) else {
         return;
+    };
+    cut.provenance.requested_start = start;
+    if cut.read(&mut reader, profile.step).is_ok() {
+        cut.finish(start_nanos, reader);
     }
+}
 
-    let mut reader = csv::Reader::from_path(clock).unwrap();
+/// Cuts without a clock csv, locating `start` with the PPS markers embedded in the audio
+#[allow(clippy::too_many_arguments)]
+pub fn make_wav_pps<P: std::convert::AsRef<Path>>(
+    output: P,
+    input_dir: P,
+    patterns: &[NamePattern],
+    start: i64,
+    samples: u64,
+    profile: &DeviceProfile,
+    layout: &PpsLayout,
+    index: &mut Index,
+    options: &Options,
+) {
+    let spec = profile.output_spec();
 
-    let records = reader
-        .deserialize()
-        .flatten()
-        //.map(|r| r.unwrap())
-        .collect::<Vec<Record>>();
+    let (markers, waves) = pps::find_best(input_dir.as_ref(), patterns, start, layout, index);
+    let Some(best) = markers.iter().min_by_key(|p| (p.nanos - start).abs()) else {
+        log::error(
+            Class::OutOfRange,
+            "No PPS markers found near requested start",
+        );
+        return;
+    };
+    let Some((_, in_spec)) = index.wav_info(&best.file) else {
+        log::error(
+            Class::Io,
+            format!("Error reading file: {}", best.file.display()),
+        );
+        return;
+    };
+    let in_channels = in_spec.channels as u32;
+    let Some((start_file, file_start_sample)) = pps::find_start(
+        start,
+        best.nanos,
+        best.sample,
+        &best.file,
+        &waves,
+        in_channels,
+        spec.sample_rate as f64,
+        index,
+    ) else {
+        log::error(
+            Class::OutOfRange,
+            "Requested start not in audio data time range",
+        );
+        return;
+    };
 
-    if records.is_empty() {
-        eprintln!("Failed to read clock csv");
+    let records = markers
+        .iter()
+        .map(|p| Record {
+            time: p.nanos,
+            sample: 0,
+            file_sample: p.sample / in_channels,
+            file: p.file.file_name().unwrap().to_str().unwrap().to_owned(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut reader =
+        TimedSampleReader::new(input_dir.as_ref(), patterns, profile.rate(), profile.step);
+    reader.seek(&start_file, file_start_sample, None, &records);
+    let Ok(mut cut) = Cut::new(output.as_ref(), spec, samples, start, options) else {
         return;
+    };
+    // The start is rounded to whole frames from the nearest marker
+    let frames = ((start - best.nanos) as f64 / 1e9 * spec.sample_rate as f64).round();
+    cut.provenance.requested_start = Some(start);
+    cut.provenance.achieved_start =
+        Some(best.nanos + (frames / spec.sample_rate as f64 * 1e9).round() as i64);
+    if cut.read(&mut reader, profile.step).is_ok() {
+        cut.finish(start, reader);
     }
+}
 
-    let n_records = records.len();
+/// An output being written from one or more stretches of input
+struct Cut {
+    writer: Writer,
+    pb: ProgressBar,
+    spec: hound::WavSpec,
+    pps: Vec<PpsMark>,
+    resampler: Option<Resampler>,
+    /// Input samples to read
+    budget: u64,
+    max_uncertainty: Option<i64>,
+    provenance: Provenance,
+}
 
-    let mut start_file = records[0].file.clone();
-    let mut file_start_sample = 0;
-    if let Some(start) = start {
-        if records[0].time - (records[0].file_sample as f64 / FREQ * 1e9).round() as i64 > start
-            || records[n_records - 1].time < start
-        {
-            file_start_sample = -1;
+impl Cut {
+    fn new(
+        output: &Path,
+        spec: hound::WavSpec,
+        samples: u64,
+        start_nanos: i64,
+        options: &Options,
+    ) -> io::Result<Self> {
+        let resampler = options
+            .resample
+            .then(|| Resampler::new(spec.channels, spec.sample_rate, start_nanos, samples));
+        // Drifting clocks may need a little more input than nominal, plus a second to reach
+        // the PPS after the end
+        let budget = if resampler.is_some() {
+            samples + samples / 1000 + (spec.sample_rate * spec.channels as u32) as u64
         } else {
-            let mut diff = i64::MAX;
-            for r in records.iter() {
-                let r_diff = (r.time - start).abs();
-                if r_diff < diff {
-                    diff = r_diff;
-                    start_file = r.file.clone();
-                    let r_diff = start - r.time;
-                    let sample_diff = (r_diff as f64 * FREQ / 1e9).round() as i64;
-                    file_start_sample = (r.file_sample as i64 + sample_diff).max(0);
-                }
-            }
-        }
-    }
-    if file_start_sample == -1 {
-        eprintln!("Requested start not in audio data time range");
-        return;
+            samples
+        };
+        let pb = log::progress_bar(budget);
+        let t = (budget as f64).log10().ceil() as u64;
+        pb.set_style(
+            ProgressStyle::with_template(&format!(
+                "[{{elapsed_precise}}] {{bar:40.cyan/blue}} {{pos:>{t}}}/{{len:{t}}} ({{percent}}%) {{msg}}"
+            ))
+            .unwrap()
+            .progress_chars("##-"),
+        );
+        // std::fs::create_dir_all(Path::new(output.as_ref()).parent().unwrap_or(Path::new(""))).unwrap();
+        Ok(Self {
+            writer: Writer::create(output, spec, options)?,
+            pb,
+            spec,
+            pps: Vec::new(),
+            resampler,
+            budget,
+            max_uncertainty: options.max_uncertainty,
+            provenance: Provenance::default(),
+        })
     }
-    let start_file = input_dir.as_ref().join(start_file);
-    let mut file_start_sample = file_start_sample as u32;
 
-    while let Some(wav) = wav_iter.peek() {
-        if **wav == start_file {
-            break;
-        }
-        wav_iter.next();
+    fn frames(&self) -> u64 {
+        self.pb.position() / self.spec.channels as u64
     }
 
-    //let start_nanos = if let Some(start) = start {
-    //    start
-    //} else {
-    //    records[0].time - (records[0].sample as f64 / FREQ * 1e9).round() as i64
-    //};
-    let end_file = records[n_records - 1].file.clone();
-    let end_file = input_dir.as_ref().join(end_file);
-
-    let mut samples = if let Some(samples) = samples {
-        samples
-    } else {
-        records[n_records - 1].sample
-    };
+    fn done(&self) -> bool {
+        self.resampler.as_ref().is_some_and(|r| r.done())
+    }
 
-    let pb = ProgressBar::new(samples);
-    let t = (samples as f64).log10().ceil() as u64;
-    pb.set_style(
-        ProgressStyle::with_template(&format!(
-            "[{{elapsed_precise}}] {{bar:40.cyan/blue}} {{pos:>{t}}}/{{len:{t}}} ({{percent}}%) {{msg}}"
-        ))
-        .unwrap()
-        .progress_chars("##-"),
-    );
-
-    // std::fs::create_dir_all(Path::new(output.as_ref()).parent().unwrap_or(Path::new(""))).unwrap();
-    let mut writer = hound::WavWriter::create(output, spec).unwrap();
-
-    let mut start = true;
-    let mut end = false;
-    for wav in wav_iter {
-        let mut reader = match hound::WavReader::open(wav) {
-            Ok(r) => r,
-            Err(e) => {
-                eprintln!("Error reading file: {e}");
-                return;
+    fn write(&mut self, sample: i32) -> io::Result<()> {
+        let writer = &mut self.writer;
+        match &mut self.resampler {
+            Some(r) => {
+                let mut result = Ok(());
+                r.push(sample, &mut |s| {
+                    if let Err(e) = writer.write_sample(s) {
+                        result = Err(e);
+                    }
+                });
+                result?;
             }
-        };
+            None => writer.write_sample(sample)?,
+        }
+        self.pb.inc(1);
+        Ok(())
+    }
 
-        if start {
-            if file_start_sample <= reader.duration() {
-                reader.seek(file_start_sample).unwrap();
-                start = false;
-            } else {
-                file_start_sample -= reader.duration();
-                continue;
-            }
+    /// Writes `samples` zero samples
+    fn fill(&mut self, samples: u64) -> io::Result<()> {
+        for _ in 0..samples {
+            self.write(0)?;
         }
+        Ok(())
+    }
 
-        for s in reader.samples::<i32>().step_by(step.unwrap_or(2)) {
-            if samples != 0 {
-                writer.write_sample(s.unwrap()).unwrap();
-                samples -= 1;
-                pb.inc(1);
-            } else {
-                end = true;
+    /// Writes the samples of `reader` until the budget is spent, filling its gaps with
+    /// silence and collecting the PPS marks of the files it passes. Stops when writing fails.
+    fn read(&mut self, reader: &mut TimedSampleReader, step: usize) -> io::Result<()> {
+        let channels = self.spec.channels as u64;
+        let mut samples = self.budget;
+        let mut records: &[Record] = &[];
+        let mut tracker: Option<PpsTracker> = None;
+        let mut anchored = 0;
+        while samples != 0 && !self.done() {
+            let Some(event) = reader.next_event() else {
                 break;
+            };
+            match event {
+                Event::Sample(sample) => {
+                    self.write(sample)?;
+                    samples -= 1;
+                }
+                Event::Gap { frames, session } => {
+                    let gap = (frames * channels).min(samples);
+                    log::warn(format!(
+                        "Filling {} frames of silence before session {}",
+                        gap / channels,
+                        session.name()
+                    ));
+                    self.provenance.gaps.push(Gap {
+                        sample: self.frames(),
+                        frames: gap / channels,
+                    });
+                    self.fill(gap)?;
+                    samples -= gap;
+                }
+                Event::Session { records: r } => {
+                    if let Some(tracker) = tracker.take() {
+                        self.pps.extend(tracker.finish(self.frames()));
+                    }
+                    records = r;
+                    anchored = 0;
+                }
+                Event::File {
+                    file,
+                    seek,
+                    channels: in_channels,
+                } => {
+                    let tracker = tracker.get_or_insert_with(|| {
+                        let ratio = in_channels as f64 / step as f64 / channels as f64;
+                        PpsTracker::new(records, ratio)
+                    });
+                    let file_name = file.file_name().unwrap().to_str().unwrap();
+                    tracker.file(file_name, seek, self.frames() as i64);
+                    if let Some(r) = &mut self.resampler {
+                        tracker.marks()[anchored..]
+                            .iter()
+                            .for_each(|m| r.anchor(*m));
+                        anchored = tracker.marks().len();
+                    }
+                }
             }
         }
+        if let Some(tracker) = tracker {
+            self.pps.extend(tracker.finish(self.frames()));
+        }
+        Ok(())
+    }
 
-        if end || *wav == end_file {
-            break;
+    fn finish(mut self, start_nanos: i64, reader: TimedSampleReader) {
+        let (read, uncertainty) = reader.finish();
+        self.provenance.sources = read.sources;
+        self.provenance.clocks = read.clocks;
+        if let Some(achieved) = read.achieved_start {
+            self.provenance.achieved_start.get_or_insert(achieved);
+        }
+        let samples_processed = self.pb.position();
+        let pps = match &mut self.resampler {
+            Some(r) => {
+                let writer = &mut self.writer;
+                // A failed write is logged and discards the output on finalizing
+                r.flush(&mut |s| {
+                    let _ = writer.write_sample(s);
+                });
+                r.marks()
+            }
+            None => self.pps,
+        };
+        let timing = Timing {
+            start_nanos: Some(start_nanos),
+            pps,
+            uncertainty,
+        };
+        if self.resampler.is_some() {
+            // Resampled outputs start exactly on the requested time
+            self.provenance.achieved_start = Some(start_nanos);
+        }
+        let message = uncertainty_message(uncertainty, self.max_uncertainty);
+        if self.writer.finalize(&timing, &self.provenance).is_err() {
+            self.pb.abandon();
+            return;
         }
+        self.pb
+            .finish_with_message(format!("Samples processed: {samples_processed}{message}"));
     }
-    let samples_processed = pb.position();
-    pb.finish_with_message(format!("Samples processed: {samples_processed}"));
 }